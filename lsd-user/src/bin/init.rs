@@ -0,0 +1,76 @@
+//! The first user program, the kernel starts it from the initrd at boot and from the shell's `run`
+//!
+//! Goes through each system call once, so a broken path shows up as soon as the kernel boots.
+
+#![no_std]
+#![no_main]
+
+use core::time::Duration;
+
+use lsd_user::{clock, prot, println};
+
+// Entry point, the kernel leaves `sp` pointing at argc with argv right above it
+core::arch::global_asm!("
+    .globl _start
+    _start:
+        mv a0, sp
+        call {}
+", sym main);
+
+extern "C" fn main(sp: *const usize) -> ! {
+    let pid = lsd_user::getpid();
+
+    let argc = unsafe { *sp };
+    let argv = unsafe { core::slice::from_raw_parts(sp.add(1) as *const *const u8, argc) };
+
+    println!("init[{}]: started with {} argument(s)", pid, argc);
+
+    for (index, &arg) in argv.iter().enumerate() {
+        println!("init[{}]: argv[{}] = {}", pid, index, unsafe { c_str(arg) });
+    }
+
+    match lsd_user::get_time(clock::MONOTONIC) {
+        Ok(time) => println!("init[{}]: up for {:?}", pid, time),
+        Err(err) => println!("init[{}]: get_time failed: {:?}", pid, err),
+    }
+
+    match lsd_user::mmap(0, 4096, prot::READ | prot::WRITE) {
+        Ok(page) => {
+            let page = unsafe { core::slice::from_raw_parts_mut(page, 4096) };
+            page.fill(0xAA);
+
+            println!("init[{}]: mapped a page at {:p}", pid, page.as_ptr());
+        },
+        Err(err) => println!("init[{}]: mmap failed: {:?}", pid, err),
+    }
+
+    lsd_user::yield_now();
+    lsd_user::sleep(Duration::from_millis(100));
+
+    match lsd_user::wait(0) {
+        Err(lsd_user::Errno::NoChild) => {},
+        other => println!("init[{}]: wait without children returned {:?}", pid, other),
+    }
+
+    println!("init[{}]: exiting", pid);
+
+    lsd_user::exit(0)
+}
+
+/// The string at `ptr` up to its nul, or a placeholder if it isn't UTF-8
+unsafe fn c_str<'a>(ptr: *const u8) -> &'a str {
+    let mut len = 0;
+
+    while *ptr.add(len) != 0 {
+        len += 1;
+    }
+
+    core::str::from_utf8(core::slice::from_raw_parts(ptr, len)).unwrap_or("<invalid utf-8>")
+}
+
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    println!("init[{}]: {}", lsd_user::getpid(), info);
+
+    lsd_user::exit(101)
+}
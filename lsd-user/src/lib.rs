@@ -0,0 +1,187 @@
+//! System calls into the lsd kernel from user mode
+//!
+//! Mirrors the ABI in the kernel's `syscall` module: the number goes in `a7`, arguments in
+//! `a0` to `a5`, and results come back in `a0` and `a1`, with errors as a negated `Errno`.
+
+#![no_std]
+
+use core::time::Duration;
+
+pub mod number {
+    pub const WRITE: usize = 1;
+    pub const EXIT: usize = 2;
+    pub const YIELD: usize = 3;
+    pub const SLEEP: usize = 4;
+    pub const MMAP: usize = 5;
+    pub const GET_TIME: usize = 6;
+    pub const GETPID: usize = 7;
+    pub const WAIT: usize = 8;
+    pub const KILL: usize = 9;
+}
+
+pub mod prot {
+    pub const READ: usize = 1 << 0;
+    pub const WRITE: usize = 1 << 1;
+    pub const EXEC: usize = 1 << 2;
+}
+
+pub mod clock {
+    pub const MONOTONIC: usize = 0;
+    pub const REALTIME: usize = 1;
+}
+
+pub const STDOUT: usize = 1;
+pub const STDERR: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Errno {
+    NoProcess,
+    Intr,
+    BadFd,
+    NoChild,
+    NoMem,
+    Fault,
+    Inval,
+    NoSys,
+    /// Something this version of the library doesn't know about
+    Other(usize),
+}
+
+impl Errno {
+    fn from_code(code: usize) -> Self {
+        match code {
+            3 => Self::NoProcess,
+            4 => Self::Intr,
+            9 => Self::BadFd,
+            10 => Self::NoChild,
+            12 => Self::NoMem,
+            14 => Self::Fault,
+            22 => Self::Inval,
+            38 => Self::NoSys,
+            code => Self::Other(code),
+        }
+    }
+}
+
+pub type Result<T> = core::result::Result<T, Errno>;
+
+/// Makes a raw system call, returning `a0` and `a1`, or the error `a0` encodes
+///
+/// # Safety
+/// The arguments have to be valid for the call, pointers especially
+pub unsafe fn syscall(number: usize, args: [usize; 6]) -> Result<(usize, usize)> {
+    let a0: usize;
+    let a1: usize;
+
+    core::arch::asm!(
+        "ecall",
+        inlateout("a0") args[0] => a0,
+        inlateout("a1") args[1] => a1,
+        in("a2") args[2],
+        in("a3") args[3],
+        in("a4") args[4],
+        in("a5") args[5],
+        in("a7") number,
+    );
+
+    // Errors are the only results in the top page of the address space
+    match (a0 as isize) < 0 && (a0 as isize) >= -4095 {
+        true => Err(Errno::from_code(a0.wrapping_neg())),
+        false => Ok((a0, a1)),
+    }
+}
+
+/// Writes `bytes` to `fd`, returning how many were written
+pub fn write(fd: usize, bytes: &[u8]) -> Result<usize> {
+    unsafe { syscall(number::WRITE, [fd, bytes.as_ptr() as usize, bytes.len(), 0, 0, 0]).map(|(written, _)| written) }
+}
+
+pub fn exit(code: isize) -> ! {
+    unsafe {
+        let _ = syscall(number::EXIT, [code as usize, 0, 0, 0, 0, 0]);
+    }
+
+    unreachable!("Exit returned");
+}
+
+pub fn yield_now() {
+    unsafe {
+        let _ = syscall(number::YIELD, [0; 6]);
+    }
+}
+
+pub fn sleep(duration: Duration) {
+    let nanos = duration.as_nanos().min(usize::MAX as u128) as usize;
+
+    unsafe {
+        let _ = syscall(number::SLEEP, [nanos, 0, 0, 0, 0, 0]);
+    }
+}
+
+/// Maps `len` bytes of zeroed memory, at `addr` or anywhere if it's 0
+pub fn mmap(addr: usize, len: usize, protection: usize) -> Result<*mut u8> {
+    unsafe { syscall(number::MMAP, [addr, len, protection, 0, 0, 0]).map(|(addr, _)| addr as *mut u8) }
+}
+
+/// Reads one of the `clock` clocks
+pub fn get_time(clock: usize) -> Result<Duration> {
+    unsafe { syscall(number::GET_TIME, [clock, 0, 0, 0, 0, 0]).map(|(secs, nanos)| Duration::new(secs as u64, nanos as u32)) }
+}
+
+pub fn getpid() -> usize {
+    unsafe { syscall(number::GETPID, [0; 6]).map_or(0, |(pid, _)| pid) }
+}
+
+/// How a child ended, decoded from the status `wait` returns
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitStatus {
+    Exited(u8),
+    Killed,
+    Faulted,
+    Unknown(usize),
+}
+
+impl WaitStatus {
+    fn from_raw(status: usize) -> Self {
+        match status {
+            9 => Self::Killed,
+            11 => Self::Faulted,
+            status if status & 0xFF == 0 => Self::Exited((status >> 8) as u8),
+            status => Self::Unknown(status),
+        }
+    }
+}
+
+/// Waits for a child to exit, any of them if `pid` is 0, returning its pid and how it ended
+pub fn wait(pid: usize) -> Result<(usize, WaitStatus)> {
+    unsafe { syscall(number::WAIT, [pid, 0, 0, 0, 0, 0]).map(|(pid, status)| (pid, WaitStatus::from_raw(status))) }
+}
+
+pub fn kill(pid: usize) -> Result<()> {
+    unsafe { syscall(number::KILL, [pid, 0, 0, 0, 0, 0]).map(|_| ()) }
+}
+
+/// Prints to stdout, errors are dropped
+#[macro_export]
+macro_rules! print {
+    ($($t:tt)*) => { $crate::_print(format_args!($($t)*)) };
+}
+
+#[macro_export]
+macro_rules! println {
+    () => { $crate::print!("\n") };
+    ($($t:tt)*) => { $crate::print!("{}\n", format_args!($($t)*)) };
+}
+
+#[doc(hidden)]
+pub fn _print(args: core::fmt::Arguments) {
+    struct Stdout;
+
+    impl core::fmt::Write for Stdout {
+        fn write_str(&mut self, s: &str) -> core::fmt::Result {
+            write(STDOUT, s.as_bytes()).map(|_| ()).map_err(|_| core::fmt::Error)
+        }
+    }
+
+    let _ = core::fmt::Write::write_fmt(&mut Stdout, args);
+}
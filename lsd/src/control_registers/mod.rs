@@ -10,7 +10,14 @@ bitflags::bitflags! {
 
     pub struct Sstatus: usize {
         const SIE = 1 << 1; // Supervisor-level interrupt enable
+        const SPIE = 1 << 5; // SIE before the trap, restored by sret
         const SPP = 1 << 8; // 0 means that USER mode is enabled
+        const FS = 0b11 << 13; // Floating point unit state, all clear means off
+        const FS_INITIAL = 0b01 << 13;
+        const FS_CLEAN = 0b10 << 13;
+        const FS_DIRTY = 0b11 << 13;
+        const SUM = 1 << 18; // Lets S-mode access user pages
+        const MXR = 1 << 19; // Lets loads read execute-only pages
     }
 
     pub struct Satp: usize {
@@ -0,0 +1,198 @@
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+
+use crate::Compat;
+use crate::interrupts::irq::{self, InterruptController};
+use crate::volatile::{Volatile, Read};
+
+use super::imsic;
+
+const DOMAINCFG: usize = 0x0000;
+const SOURCECFG: usize = 0x0004;
+const SETIPNUM_LE: usize = 0x2000;
+const CLRIE: usize = 0x1F00;
+const SETIENUM: usize = 0x1EDC;
+const CLRIENUM: usize = 0x1FDC;
+const TARGET: usize = 0x3004;
+const IDC: usize = 0x4000;
+
+const DOMAINCFG_IE: u32 = 1 << 8;
+const DOMAINCFG_DM: u32 = 1 << 2;
+
+const SOURCECFG_INACTIVE: u32 = 0;
+const SOURCECFG_LEVEL_HIGH: u32 = 6;
+
+static APLIC: Aplic = Aplic {
+    base: AtomicPtr::new(core::ptr::null_mut()),
+    sources: AtomicUsize::new(0),
+    msi: AtomicBool::new(false),
+};
+
+/// Interrupt delivery control for a single hart, only used in direct mode
+#[repr(C)]
+pub struct Idc {
+    pub idelivery: Volatile<u32>,
+    pub iforce: Volatile<u32>,
+    pub ithreshold: Volatile<u32>,
+    _reserved: [u32; 3],
+    pub topi: Volatile<u32, Read>,
+    pub claimi: Volatile<u32, Read>,
+}
+
+pub struct Aplic {
+    base: AtomicPtr<u32>,
+    sources: AtomicUsize,
+    msi: AtomicBool,
+}
+
+/// Sets up the supervisor-level APLIC, returns false if the devicetree doesn't describe one
+///
+/// If the APLIC has an `msi-parent` it forwards interrupts as MSIs to the IMSIC, otherwise
+/// they are delivered directly through each hart's IDC
+pub fn init(devicetree_ptr: *const u8) -> bool {
+    let fdt: fdt::Fdt;
+    unsafe {
+        fdt = fdt::Fdt::from_ptr(devicetree_ptr).unwrap();
+    }
+
+    // The machine-level domain delegates to its children, we want the leaf domain
+    let aplic_node = fdt.all_nodes().find(|node| {
+        let compatible = node.compatible().map(|compatible| compatible.all().any(|c| Aplic::compatible().contains(&c)));
+
+        compatible.unwrap_or(false) && node.property("riscv,children").is_none()
+    });
+
+    let aplic_node = match aplic_node {
+        None => return false,
+        Some(node) => node
+    };
+
+    log::info!("APLIC initializing...");
+
+    let region = aplic_node.reg().expect("No aplic region").next().unwrap();
+    let sources = aplic_node.property("riscv,num-sources").and_then(|sources| sources.as_usize()).expect("No aplic source count");
+    let msi = aplic_node.property("msi-parent").is_some();
+
+    if msi && !imsic::init(devicetree_ptr) {
+        panic!("APLIC is in MSI mode, but no IMSIC was found");
+    }
+
+    APLIC.base.store(region.starting_address.cast_mut() as *mut u32, Ordering::Relaxed);
+    APLIC.sources.store(sources, Ordering::Relaxed);
+    APLIC.msi.store(msi, Ordering::Relaxed);
+
+    for id in 1..=sources {
+        APLIC.write(SOURCECFG + (id - 1) * 4, SOURCECFG_INACTIVE);
+    }
+
+    for word in 0..=(sources / 32) {
+        APLIC.write(CLRIE + word * 4, u32::MAX);
+    }
+
+    let domaincfg = match msi {
+        true => DOMAINCFG_IE | DOMAINCFG_DM,
+        false => DOMAINCFG_IE
+    };
+    APLIC.write(DOMAINCFG, domaincfg);
+
+    irq::set_controller(&APLIC);
+
+    match msi {
+        true => log::info!("APLIC Enabled in MSI mode with {} sources", sources),
+        false => log::info!("APLIC Enabled in direct mode with {} sources", sources)
+    }
+
+    true
+}
+
+impl Aplic {
+    fn write(&self, offset: usize, value: u32) {
+        unsafe {
+            self.base.load(Ordering::Relaxed).byte_add(offset).write_volatile(value);
+        }
+    }
+
+    // IDCs are indexed by hart index, which matches the hart id on the virt machine
+    fn idc(&self, hart: usize) -> &Idc {
+        unsafe {&*(self.base.load(Ordering::Relaxed).byte_add(IDC + hart * 32) as *const Idc)}
+    }
+
+    fn is_msi(&self) -> bool {
+        self.msi.load(Ordering::Relaxed)
+    }
+}
+
+impl InterruptController for Aplic {
+    fn max_sources(&self) -> usize {
+        self.sources.load(Ordering::Relaxed)
+    }
+
+    fn init_hart(&self, hart: usize) {
+        match self.is_msi() {
+            true => imsic::init_hart(),
+            false => {
+                let idc = self.idc(hart);
+
+                idc.iforce.write(0);
+                idc.ithreshold.write(0);
+                idc.idelivery.write(1);
+            }
+        }
+    }
+
+    fn enable(&self, hart: usize, id: usize, priority: usize) {
+        self.write(SOURCECFG + (id - 1) * 4, SOURCECFG_LEVEL_HIGH);
+
+        let target = match self.is_msi() {
+            true => {
+                // The source id doubles as the external interrupt identity
+                imsic::enable(id);
+
+                (hart << 18) | id
+            },
+            false => {
+                // Lower values are higher priority here, unlike the PLIC
+                let iprio = 255 - priority.clamp(1, 254);
+
+                (hart << 18) | iprio
+            }
+        };
+
+        self.write(TARGET + (id - 1) * 4, target as u32);
+        self.write(SETIENUM, id as u32);
+    }
+
+    fn disable(&self, id: usize) {
+        self.write(CLRIENUM, id as u32);
+        self.write(SOURCECFG + (id - 1) * 4, SOURCECFG_INACTIVE);
+    }
+
+    fn handle_pending(&self, hart: usize, dispatch: &mut dyn FnMut(usize)) -> bool {
+        let id = match self.is_msi() {
+            true => imsic::claim(),
+            false => match self.idc(hart).claimi.read() >> 16 {
+                0 => None,
+                id => Some(id as usize)
+            }
+        };
+
+        match id {
+            None => false,
+            Some(id) => {
+                dispatch(id);
+
+                // Forwarding an MSI clears the pending bit, so retrigger it if the level is still high
+                if self.is_msi() {
+                    self.write(SETIPNUM_LE, id as u32);
+                }
+
+                true
+            }
+        }
+    }
+}
+
+impl crate::Compat for Aplic {
+    fn compatible() -> &'static [&'static str] {
+        &["riscv,aplic", "qemu,aplic"]
+    }
+}
@@ -0,0 +1,149 @@
+use alloc::boxed::Box;
+use core::convert::TryInto;
+use core::sync::atomic::{AtomicPtr, Ordering};
+use core::time::Duration;
+
+use crate::Compat;
+use crate::interrupts::irq;
+use crate::sync::IrqMutex;
+use crate::volatile::{Volatile, Read, Write};
+
+static RTC: AtomicPtr<GoldfishRtc> = AtomicPtr::new(core::ptr::null_mut());
+
+// Also serializes register access, reading `time_low` latches `time_high` for the next read
+static ALARM: IrqMutex<Option<Box<dyn FnOnce() + Send>>> = IrqMutex::new(None);
+
+#[repr(C)]
+pub struct GoldfishRtc {
+    time_low: Volatile<u32, Read>,
+    time_high: Volatile<u32, Read>,
+    alarm_low: Volatile<u32>,
+    alarm_high: Volatile<u32>,
+    irq_enabled: Volatile<u32>,
+    clear_alarm: Volatile<u32, Write>,
+    alarm_status: Volatile<u32, Read>,
+    clear_interrupt: Volatile<u32, Write>,
+}
+
+impl GoldfishRtc {
+    /// Nanoseconds since the Unix epoch
+    pub fn now(&self) -> u64 {
+        let low = self.time_low.read() as u64;
+        let high = self.time_high.read() as u64;
+
+        (high << 32) | low
+    }
+
+    fn set_alarm(&self, nanos: u64) {
+        self.irq_enabled.write(1);
+
+        // The alarm is armed by the write to `alarm_low`
+        self.alarm_high.write((nanos >> 32) as u32);
+        self.alarm_low.write(nanos as u32);
+    }
+
+    fn clear_alarm(&self) {
+        self.clear_alarm.write(1);
+        self.irq_enabled.write(0);
+    }
+
+    pub fn alarm_pending(&self) -> bool {
+        self.alarm_status.read() != 0
+    }
+}
+
+/// Finds the RTC and hooks up its interrupt, returns false if the devicetree doesn't describe one
+pub fn init(devicetree_ptr: *const u8) -> bool {
+    let fdt: fdt::Fdt;
+    unsafe {
+        fdt = fdt::Fdt::from_ptr(devicetree_ptr).unwrap();
+    }
+
+    let rtc_node = match fdt.find_compatible(GoldfishRtc::compatible()) {
+        None => return false,
+        Some(node) => node
+    };
+
+    let region = rtc_node.reg().expect("No rtc region").next().unwrap();
+    let rtc = region.starting_address.cast_mut() as *mut GoldfishRtc;
+
+    unsafe {
+        (*rtc).clear_alarm();
+        (*rtc).clear_interrupt.write(1);
+    }
+
+    RTC.store(rtc, Ordering::Relaxed);
+
+    if let Err(err) = irq::register_node(rtc_node, 1, |_| alarm_interrupt()) {
+        log::warn!("RTC alarms unavailable: {:?}", err);
+    }
+
+    log::info!("Goldfish RTC found, {} seconds since the epoch", now().unwrap_or_default().as_secs());
+
+    true
+}
+
+/// Time since the Unix epoch, None if there's no RTC
+pub fn now() -> Option<Duration> {
+    let rtc = rtc()?;
+    let _lock = ALARM.lock();
+
+    Some(Duration::from_nanos(rtc.now()))
+}
+
+/// Runs `callback` from the RTC interrupt once the wall clock reaches `at`, replacing any earlier alarm
+///
+/// Returns false if there's no RTC
+pub fn set_alarm(at: Duration, callback: impl FnOnce() + Send + 'static) -> bool {
+    let rtc = match rtc() {
+        None => return false,
+        Some(rtc) => rtc
+    };
+    let mut alarm = ALARM.lock();
+
+    *alarm = Some(Box::new(callback));
+    rtc.set_alarm(at.as_nanos().try_into().unwrap_or(u64::MAX));
+
+    true
+}
+
+pub fn cancel_alarm() {
+    if let Some(rtc) = rtc() {
+        let mut alarm = ALARM.lock();
+
+        rtc.clear_alarm();
+        *alarm = None;
+    }
+}
+
+fn alarm_interrupt() {
+    let rtc = match rtc() {
+        None => return,
+        Some(rtc) => rtc
+    };
+
+    let callback = {
+        let mut alarm = ALARM.lock();
+
+        rtc.clear_interrupt.write(1);
+        rtc.clear_alarm();
+
+        alarm.take()
+    };
+
+    if let Some(callback) = callback {
+        callback();
+    }
+}
+
+fn rtc() -> Option<&'static GoldfishRtc> {
+    let rtc = RTC.load(Ordering::Relaxed);
+
+    unsafe {rtc.as_ref()}
+}
+
+impl crate::Compat for GoldfishRtc {
+    fn compatible() -> &'static [&'static str] {
+        &["google,goldfish-rtc"]
+    }
+}
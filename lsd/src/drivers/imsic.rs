@@ -0,0 +1,155 @@
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+use crate::Compat;
+use crate::volatile::{Volatile, Write};
+
+// Indirectly accessed interrupt file registers, selected through `siselect`
+const EIDELIVERY: usize = 0x70;
+const EITHRESHOLD: usize = 0x72;
+const EIE0: usize = 0xC0;
+
+// Each supervisor interrupt file is a single page
+const FILE_STRIDE: usize = 0x1000;
+
+static BASE: AtomicPtr<u8> = AtomicPtr::new(core::ptr::null_mut());
+static IDS: AtomicUsize = AtomicUsize::new(0);
+
+#[repr(C)]
+pub struct InterruptFile {
+    pub seteipnum_le: Volatile<u32, Write>,
+    pub seteipnum_be: Volatile<u32, Write>,
+}
+
+/// Finds the supervisor-level IMSIC, returns false if the devicetree doesn't describe one
+pub fn init(devicetree_ptr: *const u8) -> bool {
+    let fdt: fdt::Fdt;
+    unsafe {
+        fdt = fdt::Fdt::from_ptr(devicetree_ptr).unwrap();
+    }
+
+    let imsic_node = fdt.all_nodes().find(|node| {
+        let compatible = node.compatible().map(|compatible| compatible.all().any(|c| InterruptFile::compatible().contains(&c)));
+
+        compatible.unwrap_or(false) && targets_supervisor(node.property("interrupts-extended"))
+    });
+
+    let imsic_node = match imsic_node {
+        None => return false,
+        Some(node) => node
+    };
+
+    let region = imsic_node.reg().expect("No imsic region").next().unwrap();
+    let ids = imsic_node.property("riscv,num-ids").and_then(|ids| ids.as_usize()).expect("No imsic id count");
+
+    BASE.store(region.starting_address.cast_mut(), Ordering::Relaxed);
+    IDS.store(ids, Ordering::Relaxed);
+
+    log::info!("IMSIC found with {} ids", ids);
+
+    true
+}
+
+pub fn num_ids() -> usize {
+    IDS.load(Ordering::Relaxed)
+}
+
+/// The interrupt file MSIs for `hart` are written to
+pub fn interrupt_file(hart: usize) -> &'static InterruptFile {
+    let base = BASE.load(Ordering::Relaxed);
+
+    unsafe {&*(base.add(hart * FILE_STRIDE) as *const InterruptFile)}
+}
+
+/// Turns on interrupt delivery from the current hart's interrupt file
+pub fn init_hart() {
+    write_indirect(EITHRESHOLD, 0);
+    write_indirect(EIDELIVERY, 1);
+}
+
+/// Enables `id` in the current hart's interrupt file
+pub fn enable(id: usize) {
+    let (reg, bit) = eie_position(id);
+
+    set_indirect(reg, bit);
+}
+
+/// Disables `id` in the current hart's interrupt file
+pub fn disable(id: usize) {
+    let (reg, bit) = eie_position(id);
+
+    clear_indirect(reg, bit);
+}
+
+/// Claims the highest priority pending id of the current hart's interrupt file
+pub fn claim() -> Option<usize> {
+    let topei: usize;
+
+    unsafe {
+        // stopei
+        core::arch::asm!(
+            "csrrw {}, 0x15C, zero",
+            out(reg) topei
+        );
+    }
+
+    match topei >> 16 {
+        0 => None,
+        id => Some(id)
+    }
+}
+
+// On RV64 only the even numbered `eie` registers exist, each covering 64 ids
+fn eie_position(id: usize) -> (usize, usize) {
+    (EIE0 + (id / 64) * 2, 1 << (id % 64))
+}
+
+fn targets_supervisor(interrupts_extended: Option<fdt::node::NodeProperty>) -> bool {
+    let interrupts_extended = match interrupts_extended {
+        None => return false,
+        Some(prop) => prop
+    };
+
+    // Pairs of (interrupt parent phandle, interrupt cause), cause 9 is the supervisor external interrupt
+    interrupts_extended.value.chunks_exact(8).any(|pair| {
+        u32::from_be_bytes([pair[4], pair[5], pair[6], pair[7]]) == 9
+    })
+}
+
+fn write_indirect(reg: usize, value: usize) {
+    unsafe {
+        core::arch::asm!(
+            "csrw 0x150, {}",
+            "csrw 0x151, {}",
+            in(reg) reg,
+            in(reg) value
+        );
+    }
+}
+
+fn set_indirect(reg: usize, bits: usize) {
+    unsafe {
+        core::arch::asm!(
+            "csrw 0x150, {}",
+            "csrs 0x151, {}",
+            in(reg) reg,
+            in(reg) bits
+        );
+    }
+}
+
+fn clear_indirect(reg: usize, bits: usize) {
+    unsafe {
+        core::arch::asm!(
+            "csrw 0x150, {}",
+            "csrc 0x151, {}",
+            in(reg) reg,
+            in(reg) bits
+        );
+    }
+}
+
+impl crate::Compat for InterruptFile {
+    fn compatible() -> &'static [&'static str] {
+        &["riscv,imsics", "qemu,imsics"]
+    }
+}
@@ -1,3 +1,6 @@
 pub mod uart;
 pub mod virtio;
-pub mod plic;
\ No newline at end of file
+pub mod plic;
+pub mod aplic;
+pub mod imsic;
+pub mod goldfish_rtc;
\ No newline at end of file
@@ -1,7 +1,14 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
 use sifive_plic::*;
 
+use crate::interrupts::irq::{self, InterruptController};
+use crate::sync::IrqMutex;
+
 pub static mut PLIC_REF: *mut Plic = core::ptr::null_mut();
 
+static PLIC: PlicController = PlicController { sources: AtomicUsize::new(0), lock: IrqMutex::new(()) };
+
 pub fn init(devicetree_ptr: *const u8, contexts: impl Iterator<Item = usize>) {
     log::info!("PLIC initializing...");
 
@@ -12,13 +19,67 @@ pub fn init(devicetree_ptr: *const u8, contexts: impl Iterator<Item = usize>) {
 
     let plic_node = fdt.find_compatible(Plic::compatible()).expect("Failed to find plic");
     let plic_region = plic_node.reg().expect("No plic region").next().unwrap();
-    
+    let sources = plic_node.property("riscv,ndev").and_then(|ndev| ndev.as_usize()).expect("No plic source count");
+
     unsafe {
         PLIC_REF = plic_region.starting_address.cast_mut() as *mut Plic;
     }
     let plic_ref = unsafe {&mut *PLIC_REF};
 
-    plic_ref.init(11, contexts);
+    // Source 0 is reserved, so the highest id is `riscv,ndev` itself
+    plic_ref.init(sources + 1, contexts);
+
+    PLIC.sources.store(sources, Ordering::Relaxed);
+    irq::set_controller(&PLIC);
+
+    log::info!("PLIC Enabled with {} sources", sources)
+}
 
-    log::info!("PLIC Enabled")
-}
\ No newline at end of file
+pub struct PlicController {
+    sources: AtomicUsize,
+    // Serializes the read-modify-write of enable bits and thresholds between harts
+    lock: IrqMutex<()>,
+}
+
+impl InterruptController for PlicController {
+    fn max_sources(&self) -> usize {
+        self.sources.load(Ordering::Relaxed)
+    }
+
+    fn init_hart(&self, hart: usize) {
+        let _lock = self.lock.lock();
+        let plic_ref = unsafe {&mut *PLIC_REF};
+
+        plic_ref.set_context_threshold(crate::context(hart), 0);
+    }
+
+    fn enable(&self, hart: usize, id: usize, priority: usize) {
+        let _lock = self.lock.lock();
+        let plic_ref = unsafe {&mut *PLIC_REF};
+
+        plic_ref.set_interrupt_priority(id, priority as _);
+        plic_ref.enable_interrupt(crate::context(hart), id);
+    }
+
+    fn disable(&self, id: usize) {
+        let _lock = self.lock.lock();
+        let plic_ref = unsafe {&mut *PLIC_REF};
+
+        // A priority of 0 never exceeds a context threshold, so the source is never delivered
+        plic_ref.set_interrupt_priority(id, 0);
+    }
+
+    fn handle_pending(&self, hart: usize, dispatch: &mut dyn FnMut(usize)) -> bool {
+        let plic_ref = unsafe {&mut *PLIC_REF};
+
+        match plic_ref.claim(crate::context(hart)) {
+            None => false,
+            Some(int_claim) => {
+                dispatch(int_claim.interrupt_id());
+                int_claim.complete();
+
+                true
+            }
+        }
+    }
+}
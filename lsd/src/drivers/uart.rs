@@ -35,6 +35,18 @@ impl Uart16550 {
         self.interrupt_enable.write(0x0);
     }
 
+    /// Interrupts once the transmitter can take another byte, on top of the receive interrupt
+    ///
+    /// A read-modify-write of IER, callers have to keep it from racing with itself
+    pub fn set_tx_int(&self, enabled: bool) {
+        let ier = self.interrupt_enable.read();
+
+        match enabled {
+            true => self.interrupt_enable.write(ier | 0x2),
+            false => self.interrupt_enable.write(ier & !0x2),
+        }
+    }
+
     pub fn init(&self) {
         self.line_control.write(0x03);
         self.int_id_fifo_control.write(0x01);
@@ -93,6 +105,16 @@ impl Uart16550 {
         self.data_register.write(data);
     }
 
+    pub fn try_write(&self, data: u8) -> bool {
+        if !self.data_empty() {
+            return false;
+        }
+
+        self.data_register.write(data);
+
+        true
+    }
+
     pub fn write_str(&self, s: &str) {
         for byte in s.bytes() {
             self.write(byte);
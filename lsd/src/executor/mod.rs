@@ -0,0 +1,130 @@
+//! A small executor for interrupt-driven I/O written as futures
+//!
+//! Tasks run on a dedicated kernel thread, which sleeps while none of them can make progress.
+//! Wakers only take interrupt-safe locks, so they can be woken straight from a trap handler.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+
+use crate::sync::{IrqMutex, Mutex, WaitQueue};
+use crate::thread::{self, Thread};
+
+mod sleep;
+
+pub use sleep::{sleep, sleep_until, Sleep};
+
+type BoxFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+static READY: IrqMutex<VecDeque<Arc<Task>>> = IrqMutex::new(VecDeque::new());
+static RUNNER: WaitQueue = WaitQueue::new();
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+struct Task {
+    future: Mutex<Option<BoxFuture>>,
+    // Keeps a task that's woken several times from being queued more than once
+    queued: AtomicBool,
+}
+
+impl Wake for Task {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref()
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        if self.queued.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        READY.lock().push_back(self.clone());
+        RUNNER.notify_one();
+    }
+}
+
+/// Starts the thread that runs spawned tasks, only the first call does anything
+pub fn init() {
+    if STARTED.swap(true, Ordering::AcqRel) {
+        return;
+    }
+
+    thread::spawn("executor", run);
+}
+
+/// Queues a future to run on the executor thread
+pub fn spawn(future: impl Future<Output = ()> + Send + 'static) {
+    let task = Arc::new(Task {
+        future: Mutex::new(Some(Box::pin(future))),
+        queued: AtomicBool::new(false),
+    });
+
+    task.wake_by_ref();
+}
+
+fn run() -> ! {
+    loop {
+        let mut task = None;
+
+        RUNNER.wait_until(|| {
+            task = READY.lock().pop_front();
+            task.is_some()
+        });
+
+        poll(task.unwrap());
+    }
+}
+
+fn poll(task: Arc<Task>) {
+    // Cleared first so a wake during the poll queues it again
+    task.queued.store(false, Ordering::Release);
+
+    let mut slot = task.future.lock();
+
+    let future = match slot.as_mut() {
+        None => return,
+        Some(future) => future
+    };
+
+    let waker = Waker::from(task.clone());
+
+    if future.as_mut().poll(&mut Context::from_waker(&waker)).is_ready() {
+        *slot = None;
+    }
+}
+
+// Without a thread to unblock, `block_on` just polls again
+struct ThreadWaker(Option<Arc<Thread>>);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref()
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        if let Some(thread) = &self.0 {
+            thread::unblock(thread);
+        }
+    }
+}
+
+/// Runs a future to completion on the current thread, blocking it whenever the future is pending
+///
+/// Before threads are up on this hart it polls in a loop instead
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = Box::pin(future);
+
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::try_current())));
+    let mut cx = Context::from_waker(&waker);
+
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+
+        thread::block();
+    }
+}
@@ -0,0 +1,70 @@
+use alloc::sync::Arc;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+
+use crate::sync::IrqMutex;
+use crate::timing::timer::{self, TimerId};
+use crate::timing::Instant;
+
+/// Completes once its deadline has passed, backed by a one-shot timer on the hart that first polls it
+pub struct Sleep {
+    deadline: Instant,
+    armed: Option<(TimerId, Arc<IrqMutex<Option<Waker>>>)>,
+}
+
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(Instant::now() + duration)
+}
+
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    Sleep { deadline, armed: None }
+}
+
+impl Sleep {
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if Instant::now() >= self.deadline {
+            return Poll::Ready(());
+        }
+
+        match &self.armed {
+            // Polled again, possibly by a different task, so the waker may have changed
+            Some((_, slot)) => *slot.lock() = Some(cx.waker().clone()),
+            None => {
+                let slot = Arc::new(IrqMutex::new(Some(cx.waker().clone())));
+                let fired = slot.clone();
+
+                let id = timer::at(self.deadline, move || {
+                    let waker = fired.lock().take();
+
+                    if let Some(waker) = waker {
+                        waker.wake();
+                    }
+                });
+
+                self.armed = Some((id, slot));
+            }
+        }
+
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some((id, slot)) = self.armed.take() {
+            // Cancelling only works on the hart the timer was set on, so also make sure a late one does nothing
+            *slot.lock() = None;
+            timer::cancel(id);
+        }
+    }
+}
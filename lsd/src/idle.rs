@@ -0,0 +1,184 @@
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU64, Ordering};
+use core::time::Duration;
+
+use crate::interrupts::InterruptGuard;
+use crate::smp::suspend::{self, SuspendKind};
+use crate::timing::{self, timer};
+
+const ZERO: AtomicU64 = AtomicU64::new(0);
+const AWAKE: AtomicBool = AtomicBool::new(false);
+const WFI: AtomicU8 = AtomicU8::new(IdleState::Wfi as u8);
+
+// Indexed by hart id, kept global so any hart can report on the others
+static ONLINE_SINCE: [AtomicU64; crate::MAX_HARTS] = [ZERO; crate::MAX_HARTS];
+static IDLE_TICKS: [AtomicU64; crate::MAX_HARTS] = [ZERO; crate::MAX_HARTS];
+static IDLE_STATE: [AtomicU8; crate::MAX_HARTS] = [WFI; crate::MAX_HARTS];
+static SLEEPING: [AtomicBool; crate::MAX_HARTS] = [AWAKE; crate::MAX_HARTS];
+
+/// How deeply a hart sleeps when it has nothing to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum IdleState {
+    Wfi,
+    Retentive,
+    NonRetentive,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IdleStats {
+    pub idle: Duration,
+    pub busy: Duration,
+}
+
+/// Starts idle accounting for the current hart
+pub fn init_hart() {
+    let hart = crate::HART_ID.load(Ordering::Relaxed);
+
+    ONLINE_SINCE[hart].store(timing::ticks(), Ordering::Relaxed);
+    IDLE_TICKS[hart].store(0, Ordering::Relaxed);
+}
+
+/// Sleeps the current hart until the next interrupt, unless there's already something to do
+///
+/// Returns once any interrupt that woke the hart has been handled
+pub fn idle_once() {
+    idle_unless(|| false);
+}
+
+/// Idles the current hart until `condition` holds, the condition is checked with interrupts disabled
+/// so a wakeup between the check and `wfi` can't be missed
+pub fn idle_until(condition: impl Fn() -> bool) {
+    while !idle_unless(&condition) {}
+}
+
+/// Runs ready threads, and sleeps whenever there are none
+///
+/// Also where a hart that's been marked offline finally stops, once nothing else runs on it
+pub fn idle_loop() -> ! {
+    let hart = crate::HART_ID.load(Ordering::Relaxed);
+
+    loop {
+        crate::thread::yield_now();
+
+        if !crate::smp::is_online(hart) {
+            crate::smp::stop_current();
+        }
+
+        idle_until(|| crate::thread::has_ready() || !crate::smp::is_online(hart));
+    }
+}
+
+/// Picks how `hart` sleeps the next time it idles
+pub fn set_idle_state(hart: usize, state: IdleState) {
+    IDLE_STATE[hart].store(state as u8, Ordering::Relaxed);
+}
+
+pub fn idle_state(hart: usize) -> IdleState {
+    match IDLE_STATE[hart].load(Ordering::Relaxed) {
+        0 => IdleState::Wfi,
+        1 => IdleState::Retentive,
+        _ => IdleState::NonRetentive,
+    }
+}
+
+/// Whether `hart` is asleep waiting for an interrupt, or about to be
+pub fn is_sleeping(hart: usize) -> bool {
+    SLEEPING[hart].load(Ordering::SeqCst)
+}
+
+pub fn stats(hart: usize) -> IdleStats {
+    let online = timing::ticks().saturating_sub(ONLINE_SINCE[hart].load(Ordering::Relaxed));
+    let idle = IDLE_TICKS[hart].load(Ordering::Relaxed).min(online);
+
+    IdleStats { idle: timing::ticks_to_duration(idle), busy: timing::ticks_to_duration(online - idle) }
+}
+
+/// Logs idle and busy time for every hart that has been brought online
+pub fn report() {
+    for hart in 0..crate::MAX_HARTS {
+        if ONLINE_SINCE[hart].load(Ordering::Relaxed) == 0 {
+            continue;
+        }
+
+        let stats = stats(hart);
+        let total = (stats.idle + stats.busy).as_micros().max(1);
+
+        log::info!("Hart {}: idle {:?}, busy {:?} ({}% idle)", hart, stats.idle, stats.busy, stats.idle.as_micros() * 100 / total);
+    }
+}
+
+// Returns true if `condition` held, otherwise sleeps and returns false once woken
+fn idle_unless(condition: impl Fn() -> bool) -> bool {
+    let _guard = InterruptGuard::new();
+    let hart = crate::HART_ID.load(Ordering::Relaxed);
+
+    // Announced before the last look at `condition`, so whoever makes it true afterwards knows
+    // to send an IPI
+    SLEEPING[hart].store(true, Ordering::SeqCst);
+    core::sync::atomic::fence(Ordering::SeqCst);
+
+    let held = check_and_sleep(hart, condition);
+
+    SLEEPING[hart].store(false, Ordering::Relaxed);
+
+    held
+}
+
+fn check_and_sleep(hart: usize, condition: impl Fn() -> bool) -> bool {
+    if condition() {
+        return true;
+    }
+
+    let deadline = timer::next_deadline();
+
+    match deadline {
+        // Already expired, the timer interrupt just has to be let in
+        Some(deadline) if deadline.ticks() <= timing::ticks() => return false,
+        Some(deadline) => timing::set_deadline(deadline.ticks()),
+        None => timing::set_deadline(u64::MAX),
+    }
+
+    if interrupt_pending() {
+        return false;
+    }
+
+    let start = timing::ticks();
+
+    sleep(idle_state(hart));
+
+    IDLE_TICKS[hart].fetch_add(timing::ticks() - start, Ordering::Relaxed);
+
+    false
+}
+
+fn sleep(state: IdleState) {
+    let kind = match state {
+        IdleState::Wfi => return crate::wfi(),
+        IdleState::Retentive => SuspendKind::Retentive,
+        IdleState::NonRetentive => SuspendKind::NonRetentive,
+    };
+
+    if let Err(err) = suspend::suspend(kind) {
+        log::warn!("{:?} suspend failed, falling back to wfi: {:?}", kind, err);
+        set_idle_state(crate::HART_ID.load(Ordering::Relaxed), IdleState::Wfi);
+
+        crate::wfi();
+    }
+}
+
+// `wfi` falls straight through for these anyway, but there's no sense counting that as idle
+fn interrupt_pending() -> bool {
+    let pending: usize;
+    let enabled: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "csrr {}, sip",
+            "csrr {}, sie",
+            out(reg) pending,
+            out(reg) enabled
+        );
+    }
+
+    pending & enabled != 0
+}
@@ -0,0 +1,65 @@
+use crate::control_registers::{Sie, Sstatus};
+
+/// Disables interrupts on the current hart until dropped, then restores whatever state they were in
+pub struct InterruptGuard {
+    was_enabled: bool,
+}
+
+impl InterruptGuard {
+    pub fn new() -> Self {
+        let previous: usize;
+
+        unsafe {
+            core::arch::asm!(
+                "csrrci {}, sstatus, 2",
+                out(reg) previous
+            );
+        }
+
+        Self { was_enabled: previous & Sstatus::SIE.bits() != 0 }
+    }
+}
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        if self.was_enabled {
+            unsafe {
+                core::arch::asm!("csrsi sstatus, 2");
+            }
+        }
+    }
+}
+
+/// Returns true if interrupts are enabled on the current hart
+pub fn enabled() -> bool {
+    Sstatus::read().contains(Sstatus::SIE)
+}
+
+/// Runs `f` with interrupts disabled on the current hart
+pub fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    let _guard = InterruptGuard::new();
+
+    f()
+}
+
+/// Runs `f` from inside a trap handler with interrupts re-enabled
+///
+/// External interrupts stay masked so the source being handled can't re-enter,
+/// but timer and software interrupts are still able to preempt `f`
+pub fn nested<R>(f: impl FnOnce() -> R) -> R {
+    let sie = Sie::read();
+
+    unsafe {
+        (sie - Sie::SEIE).write();
+        core::arch::asm!("csrsi sstatus, 2");
+    }
+
+    let result = f();
+
+    unsafe {
+        core::arch::asm!("csrci sstatus, 2");
+        sie.write();
+    }
+
+    result
+}
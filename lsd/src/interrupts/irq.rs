@@ -0,0 +1,186 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::Ordering;
+
+use crate::sync::IrqMutex;
+
+pub type Handler = Arc<dyn Fn(usize) + Send + Sync>;
+
+/// An external interrupt controller that device interrupts are routed through
+pub trait InterruptController: Send + Sync {
+    /// Highest interrupt id the controller can deliver
+    fn max_sources(&self) -> usize;
+
+    /// Prepares the given hart to receive external interrupts
+    fn init_hart(&self, hart: usize);
+
+    /// Sets the priority of `id` and routes it to the given hart, which has to be the current one
+    fn enable(&self, hart: usize, id: usize, priority: usize);
+
+    /// Stops `id` from being delivered to any hart
+    fn disable(&self, id: usize);
+
+    /// Claims one pending interrupt for the given hart, passes its id to `dispatch`,
+    /// and completes it afterwards. Returns false if nothing was pending.
+    fn handle_pending(&self, hart: usize, dispatch: &mut dyn FnMut(usize)) -> bool;
+}
+
+struct Registration {
+    priority: usize,
+    /// The one hart the interrupt is delivered to
+    hart: usize,
+    nested: bool,
+    handler: Handler,
+}
+
+#[derive(Debug)]
+pub enum IrqError {
+    NoController,
+    NoInterrupts,
+    InvalidId(usize),
+    AlreadyRegistered(usize),
+}
+
+static CONTROLLER: spin::Once<&'static dyn InterruptController> = spin::Once::new();
+static HANDLERS: IrqMutex<BTreeMap<usize, Registration>> = IrqMutex::new(BTreeMap::new());
+
+/// Brings up whichever interrupt controller the devicetree describes, preferring the APLIC
+pub fn init_controller(devicetree_ptr: *const u8) {
+    if crate::aplic::init(devicetree_ptr) {
+        return;
+    }
+
+    let fdt = unsafe {fdt::Fdt::from_ptr(devicetree_ptr).expect("Failed to get fdt")};
+    let contexts = fdt.cpus().map(|cpu| crate::context(cpu.ids().first()));
+
+    crate::plic::init(devicetree_ptr, contexts);
+}
+
+/// Sets the controller all registrations go through, only the first call has any effect
+pub fn set_controller(controller: &'static dyn InterruptController) {
+    CONTROLLER.call_once(|| controller);
+}
+
+pub fn controller() -> Option<&'static dyn InterruptController> {
+    CONTROLLER.get().copied()
+}
+
+/// Registers `handler` for the interrupt `id`, and routes it to the current hart
+pub fn register(id: usize, priority: usize, handler: impl Fn(usize) + Send + Sync + 'static) -> Result<(), IrqError> {
+    let controller = controller().ok_or(IrqError::NoController)?;
+
+    if id == 0 || id > controller.max_sources() {
+        return Err(IrqError::InvalidId(id));
+    }
+
+    let mut handlers = HANDLERS.lock();
+
+    if handlers.contains_key(&id) {
+        return Err(IrqError::AlreadyRegistered(id));
+    }
+
+    let hart = crate::HART_ID.load(Ordering::Relaxed);
+
+    handlers.insert(id, Registration { priority, hart, nested: false, handler: Arc::new(handler) });
+    controller.enable(hart, id, priority);
+
+    Ok(())
+}
+
+/// Registers `handler` for the first interrupt listed in the node's `interrupts` property
+///
+/// Returns the interrupt id that was registered
+pub fn register_node(node: fdt::node::FdtNode, priority: usize, handler: impl Fn(usize) + Send + Sync + 'static) -> Result<usize, IrqError> {
+    let id = node_interrupt(node).ok_or(IrqError::NoInterrupts)?;
+
+    register(id, priority, handler)?;
+
+    Ok(id)
+}
+
+/// Lets the handler for `id` run with timer and software interrupts enabled
+pub fn allow_nesting(id: usize) {
+    if let Some(registration) = HANDLERS.lock().get_mut(&id) {
+        registration.nested = true;
+    }
+}
+
+pub fn unregister(id: usize) {
+    if HANDLERS.lock().remove(&id).is_some() {
+        if let Some(controller) = controller() {
+            controller.disable(id);
+        }
+    }
+}
+
+/// Gets the current hart ready for external interrupts, and enables the registered sources routed to it
+pub fn init_hart() {
+    let controller = match controller() {
+        None => return,
+        Some(controller) => controller
+    };
+    let hart = crate::HART_ID.load(Ordering::Relaxed);
+
+    controller.init_hart(hart);
+
+    for (id, registration) in HANDLERS.lock().iter().filter(|(_, registration)| registration.hart == hart) {
+        controller.enable(hart, *id, registration.priority);
+    }
+}
+
+/// Routes every interrupt delivered to `from` to `to` instead, for when `from` goes offline
+pub fn retarget(from: usize, to: usize) -> Result<(), crate::smp::ipi::IpiError> {
+    let moved: Vec<(usize, usize)> = HANDLERS.lock()
+        .iter_mut()
+        .filter(|(_, registration)| registration.hart == from)
+        .map(|(id, registration)| {
+            registration.hart = to;
+            (*id, registration.priority)
+        })
+        .collect();
+
+    if moved.is_empty() {
+        return Ok(());
+    }
+
+    // Done from `to` itself, an IMSIC only takes enables from its own hart
+    crate::smp::ipi::call(to, move || {
+        if let Some(controller) = controller() {
+            for (id, priority) in moved {
+                controller.enable(to, id, priority);
+            }
+        }
+    })
+}
+
+/// Handles every pending external interrupt for the current hart
+pub fn dispatch() {
+    let controller = match controller() {
+        None => {
+            log::error!("External interrupt occured without a controller");
+            return;
+        },
+        Some(controller) => controller
+    };
+    let hart = crate::HART_ID.load(Ordering::Relaxed);
+
+    while controller.handle_pending(hart, &mut |id| {
+        // Clone the handler out so it can register or unregister interrupts itself
+        let handler = HANDLERS.lock().get(&id).map(|registration| (registration.handler.clone(), registration.nested));
+
+        match handler {
+            Some((handler, true)) => super::guard::nested(|| handler(id)),
+            Some((handler, false)) => handler(id),
+            None => log::error!("Unrecognized external interrupt: {}", id)
+        }
+    }) {}
+}
+
+/// The interrupt id is always the first cell of an `interrupts` specifier
+fn node_interrupt(node: fdt::node::FdtNode) -> Option<usize> {
+    let interrupts = node.property("interrupts")?;
+    let cell = interrupts.value.get(0..4)?;
+
+    Some(u32::from_be_bytes([cell[0], cell[1], cell[2], cell[3]]) as usize)
+}
@@ -0,0 +1,99 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use super::TrapMode;
+
+const SSIP: usize = 1 << 1;
+
+static MEASURING: AtomicBool = AtomicBool::new(false);
+static TRAP_END: AtomicU64 = AtomicU64::new(0);
+
+/// Cycles between raising a software interrupt and its handler running
+#[derive(Debug, Clone, Copy)]
+pub struct Latency {
+    pub min: u64,
+    pub max: u64,
+    pub average: u64,
+}
+
+/// Called from the software interrupt handler, timestamps it if a measurement is running
+pub(super) fn record() {
+    if !MEASURING.load(Ordering::Relaxed) {
+        return;
+    }
+
+    TRAP_END.store(cycles(), Ordering::Relaxed);
+
+    unsafe {
+        core::arch::asm!("csrc sip, {}", in(reg) SSIP);
+    }
+}
+
+/// Measures trap latency in the given mode by raising software interrupts on the current hart
+///
+/// Interrupts must already be enabled, and the previous trap mode is restored afterwards
+pub fn measure(mode: TrapMode, samples: usize) -> Latency {
+    let previous = TrapMode::current();
+    let mut latency = Latency { min: u64::MAX, max: 0, average: 0 };
+    let mut total = 0;
+
+    unsafe {
+        super::set_trap_mode(mode);
+    }
+    MEASURING.store(true, Ordering::Relaxed);
+
+    for _ in 0..samples {
+        TRAP_END.store(0, Ordering::Relaxed);
+
+        let start = cycles();
+        unsafe {
+            core::arch::asm!("csrs sip, {}", in(reg) SSIP);
+        }
+
+        while TRAP_END.load(Ordering::Relaxed) == 0 {
+            core::hint::spin_loop();
+        }
+
+        let elapsed = TRAP_END.load(Ordering::Relaxed) - start;
+
+        latency.min = latency.min.min(elapsed);
+        latency.max = latency.max.max(elapsed);
+        total += elapsed;
+    }
+
+    MEASURING.store(false, Ordering::Relaxed);
+    unsafe {
+        super::set_trap_mode(previous);
+    }
+
+    latency.average = total / samples.max(1) as u64;
+
+    latency
+}
+
+/// Logs the trap latency of both modes
+pub fn report(samples: usize) {
+    // The raised interrupts would never be taken, and `measure` would wait on them forever
+    if !crate::control_registers::Sie::read().contains(crate::control_registers::Sie::SSIE) {
+        log::warn!("Software interrupts are disabled, not measuring trap latency");
+        return;
+    }
+
+    for mode in [TrapMode::Direct, TrapMode::Vectored] {
+        let latency = measure(mode, samples);
+
+        log::info!("{:?} trap latency: min {}, avg {}, max {} cycles", mode, latency.min, latency.average, latency.max);
+    }
+}
+
+fn cycles() -> u64 {
+    let cycles: u64;
+
+    unsafe {
+        core::arch::asm!(
+            "rdcycle {}",
+            out(reg) cycles
+        );
+    }
+
+    cycles
+}
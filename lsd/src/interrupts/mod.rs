@@ -1,28 +1,42 @@
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
 use log::{log, Level};
 
+pub mod guard;
+pub mod irq;
+pub mod latency;
+
+pub use guard::{InterruptGuard, without_interrupts};
+
+#[thread_local]
+static DEPTH: AtomicUsize = AtomicUsize::new(0);
+
 #[thread_local]
 static mut INT_SSCRATCH: Sscratch = Sscratch { 
     kernel_stack_top: core::ptr::null_mut(),
     kernel_thread_local: core::ptr::null_mut(),
     kernel_global_ptr: core::ptr::null_mut(),
-    scratch_sp: 0
+    scratch_sp: 0,
+    scratch_reg: 0
 };
 
+/// Sets up trap handling on the boot hart
 pub fn init() {
+    let int_stack_top = crate::mem::MEM_VEC.lock().find_id("int_stack0").unwrap().base();
+
+    init_hart(int_stack_top);
+}
+
+/// Sets up trap handling on the current hart, `int_stack_top` is used for traps taken from user mode
+pub fn init_hart(int_stack_top: *mut u8) {
     use super::control_registers;
     
     unsafe {
-        set_handler_fn(int_handler);
-        log!(Level::Info, "Set vector of handler");
-        let sie = control_registers::Sie::all() | control_registers::Sie::read();
-        let sstatus = control_registers::Sstatus::read() | control_registers::Sstatus::SIE;
-        //log!(Level::Debug, "SIE: {:?}, SSTATUS: {:?}", sie, sstatus);
-        sie.write();
-        sstatus.write();
-
-        INT_SSCRATCH.kernel_thread_local = crate::utils::linker::__tdata_start.as_ptr().cast_mut();
+        // sscratch has to be valid before the first trap can be taken
+        INT_SSCRATCH.kernel_thread_local = crate::thread_pointer();
         INT_SSCRATCH.kernel_global_ptr = crate::utils::linker::__global_pointer.as_ptr().cast_mut();
-        INT_SSCRATCH.kernel_stack_top = crate::mem::MEM_VEC.lock().find_id("int_stack0").unwrap().base();
+        INT_SSCRATCH.kernel_stack_top = int_stack_top;
+        crate::percpu::PerCpu::current().int_stack_top.store(int_stack_top as usize, Ordering::Relaxed);
         let sscratch_ref = (&INT_SSCRATCH as *const Sscratch) as usize;
 
         core::arch::asm!(
@@ -30,13 +44,90 @@ pub fn init() {
             in(reg) sscratch_ref
         );
 
+        let mode = TrapMode::configured();
+        set_trap_mode(mode);
+        log!(Level::Info, "Set vector of handler, {:?} mode", mode);
+        let sie = control_registers::Sie::all() | control_registers::Sie::read();
+        // User memory is only reachable through the explicit user access paths
+        let sstatus = (control_registers::Sstatus::read() | control_registers::Sstatus::SIE) - control_registers::Sstatus::SUM;
+        //log!(Level::Debug, "SIE: {:?}, SSTATUS: {:?}", sie, sstatus);
+        sie.write();
+        sstatus.write();
+
         log::info!("Interrupts enabled")
     }
 }
 
+static VECTORED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrapMode {
+    /// Every trap enters `int_handler` and decodes `scause`
+    Direct,
+    /// Software, timer, and external interrupts enter their own stubs
+    Vectored,
+}
+
+impl TrapMode {
+    /// The mode selected by the `trap.vectored` feature
+    pub fn configured() -> Self {
+        match cfg!(feature = "trap.vectored") {
+            true => Self::Vectored,
+            false => Self::Direct
+        }
+    }
+
+    pub fn current() -> Self {
+        match VECTORED.load(Ordering::Relaxed) {
+            true => Self::Vectored,
+            false => Self::Direct
+        }
+    }
+}
+
 /// Sets the trap handler address to the given function
 pub unsafe fn set_handler_fn(f: extern "C" fn()) {
     core::arch::asm!("csrw stvec, {}", in(reg) f);
+    VECTORED.store(false, Ordering::Relaxed);
+}
+
+/// Points `stvec` at the trap entry for the given mode
+pub unsafe fn set_trap_mode(mode: TrapMode) {
+    match mode {
+        TrapMode::Direct => set_handler_fn(int_handler),
+        TrapMode::Vectored => {
+            // The low bits of `stvec` select the mode, 1 is vectored
+            let base = trap_vector_table as extern "C" fn() as usize;
+            core::arch::asm!("csrw stvec, {}", in(reg) base | 1);
+            VECTORED.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// How many traps deep the current hart is, 0 when it isn't handling one
+pub fn depth() -> usize {
+    DEPTH.load(Ordering::Relaxed)
+}
+
+pub fn in_trap() -> bool {
+    depth() > 0
+}
+
+/// Tracks the nesting depth for as long as a trap handler runs
+struct Nesting;
+
+impl Nesting {
+    fn enter() -> Self {
+        DEPTH.fetch_add(1, Ordering::Relaxed);
+
+        Self
+    }
+}
+
+impl Drop for Nesting {
+    fn drop(&mut self) {
+        DEPTH.fetch_sub(1, Ordering::Relaxed);
+    }
 }
 
 pub fn interrupt_vector() -> (bool, u64) {
@@ -58,6 +149,46 @@ pub fn interrupt_vector() -> (bool, u64) {
 pub struct TrapFrame {
     pub sepc: usize,
     pub registers: GeneralRegisters,
+    pub sstatus: usize,
+    pub scause: usize,
+}
+
+impl TrapFrame {
+    /// A frame that `sret`s to `entry` in user mode with `sp` as its stack and interrupts on
+    pub fn new_user(entry: usize, sp: usize) -> Self {
+        use crate::control_registers::Sstatus;
+
+        let sstatus: usize;
+
+        unsafe {
+            core::arch::asm!("csrr {}, sstatus", out(reg) sstatus);
+        }
+
+        // SPP clear returns to user mode, SUM stays clear so the kernel can't touch user memory by accident
+        let sstatus = (sstatus | Sstatus::SPIE.bits()) & !(Sstatus::SPP | Sstatus::SIE | Sstatus::SUM).bits();
+
+        let mut registers = GeneralRegisters::default();
+        registers.sp = sp;
+
+        Self { sepc: entry, registers, sstatus, scause: 0 }
+    }
+
+    /// Whether the trap was taken from user mode
+    pub fn from_user(&self) -> bool {
+        self.sstatus & crate::control_registers::Sstatus::SPP.bits() == 0
+    }
+
+    /// Whether interrupts were enabled in the interrupted context
+    pub fn interrupts_were_enabled(&self) -> bool {
+        self.sstatus & crate::control_registers::Sstatus::SPIE.bits() != 0
+    }
+}
+
+/// Where traps from user mode save their frame, normally the top of the running thread's kernel stack
+pub(crate) fn set_kernel_stack(top: usize) {
+    unsafe {
+        INT_SSCRATCH.kernel_stack_top = top as *mut u8;
+    }
 }
 
 #[repr(C)]
@@ -66,26 +197,104 @@ pub struct Sscratch {
     pub kernel_thread_local: *mut u8,
     pub kernel_global_ptr: *mut u8,
     pub scratch_sp: usize,
+    pub scratch_reg: usize,
+}
+
+/// Restores the `TrapFrame` at `sp` and returns with `sret`, the tail end of every trap
+macro_rules! trap_restore_asm {
+    () => {
+        r#"
+            // Restore `sepc`
+            ld t6, 0(sp)
+            csrw sepc, t6
+
+            // Restore `sstatus`, so `sret` returns to the privilege and interrupt state that was interrupted.
+            // The FS bits are kept as they are now, since the lazy FP switch owns them
+            ld t6, 256(sp)
+            csrr t5, sstatus
+            li t4, (0b11 << 13)
+            and t5, t5, t4
+            not t4, t4
+            and t6, t6, t4
+            or t6, t6, t5
+            csrw sstatus, t6
+            ld ra, 8(sp)
+
+            // Skip sp for... obvious reasons
+            ld gp, 24(sp)
+            ld tp, 32(sp)
+            ld t0, 40(sp)
+            ld t1, 48(sp)
+            ld t2, 56(sp)
+            ld s0, 64(sp)
+            ld s1, 72(sp)
+            ld a0, 80(sp)
+            ld a1, 88(sp)
+            ld a2, 96(sp)
+            ld a3, 104(sp)
+            ld a4, 112(sp)
+            ld a5, 120(sp)
+            ld a6, 128(sp)
+            ld a7, 136(sp)
+            ld s2, 144(sp)
+            ld s3, 152(sp)
+            ld s4, 160(sp)
+            ld s5, 168(sp)
+            ld s6, 176(sp)
+            ld s7, 184(sp)
+            ld s8, 192(sp)
+            ld s9, 200(sp)
+            ld s10, 208(sp)
+            ld s11, 216(sp)
+            ld t3, 224(sp)
+            ld t4, 232(sp)
+            ld t5, 240(sp)
+            ld t6, 248(sp)
+
+            // Clear any outstanding atomic reservations
+            sc.d zero, zero, 0(sp)
+
+            // Restore `sp`
+            ld sp, 16(sp)
+            
+            // gtfo
+            sret
+            "#
+    };
 }
 
 // Repnops code... again... ty, Vanadinite
-#[naked]
-#[repr(align(4))]
-pub extern "C" fn int_handler() {
-    unsafe {
-        core::arch::asm!(
+/// Saves a `TrapFrame` on the interrupt stack, calls `{handler}`, then restores it and returns with `sret`
+macro_rules! trap_entry_asm {
+    () => {
+        concat!(
             r#"
             // Interrupts are disabled when we enter a trap
             // Switch `t6` and `sscratch`
             csrrw t6, sscratch, t6
 
-            // Store current stack pointer temporarily
+            // Store current stack pointer and `t5` temporarily
             sd sp, 24(t6)
+            sd t5, 32(t6)
+
+            // Traps taken from S-mode stay on the interrupted stack, so a nested
+            // trap never overwrites the frame of the trap it interrupted
+            csrr t5, sstatus
+            andi t5, t5, 1 << 8
+            beqz t5, 3f
+
+            // Kernel trap entry
+            addi sp, sp, {TRAP_FRAME_SIZE}
+            j 4f
 
-            // Load kernel's stack pointer
+            // User trap entry, load kernel's stack pointer
+            3:
             ld sp, 0(t6)
             addi sp, sp, {TRAP_FRAME_SIZE}
 
+            4:
+            ld t5, 32(t6)
+
             // ###############################################
             // # Begin storing userspace state in trap frame #
             // ###############################################
@@ -132,9 +341,13 @@ pub extern "C" fn int_handler() {
             csrrw t6, sscratch, t6
             sd t6, 248(sp)
 
-            // Save `sepc`
+            // Save `sepc`, `sstatus`, and `scause`, a nested trap overwrites all of them
             csrr t6, sepc
             sd t6, 0(sp)
+            csrr t6, sstatus
+            sd t6, 256(sp)
+            csrr t6, scause
+            sd t6, 264(sp)
             mv a0, sp
             csrr a1, scause
             csrr a2, stval
@@ -147,7 +360,7 @@ pub extern "C" fn int_handler() {
             
             // Skip FP reg saving if they're clean
             bne s0, s1, 1f
-            addi sp, sp, -264
+            addi sp, sp, -272
             .attribute arch, "rv64imafdc"
             fsd f0, 0(sp)
             fsd f1, 8(sp)
@@ -189,12 +402,14 @@ pub extern "C" fn int_handler() {
 
             // FP registers clean
             1:
-            call handler
+            call {handler}
 
             // Check FP register status again
             bne s0, s1, 2f
 
-            // Restore if they were dirty
+            // Restore if they were dirty, a context switch in the handler may have turned the FPU off
+            li t1, (0b01 << 13)
+            csrs sstatus, t1
             .attribute arch, "rv64imafdc"
             fld f0, 0(sp)
             fld f1, 8(sp)
@@ -231,61 +446,129 @@ pub extern "C" fn int_handler() {
             ld t1, 256(sp)
             fscsr t1
             .attribute arch, "rv64imac"
-            addi sp, sp, 264
+            addi sp, sp, 272
 
             // FP registers clean
             2:
 
-            // Restore `sepc`
-            ld t6, 0(sp)
-            csrw sepc, t6
+            "#,
+            trap_restore_asm!()
+        )
+    };
+}
 
-            // Reenable interrupts after sret (set SPIE)
-            li t6, 1 << 5
-            csrs sstatus, t6
-            ld ra, 8(sp)
+/// Drops into whatever `frame` describes, which is how a thread first gets to user mode
+///
+/// `frame` is copied to the top of the current thread's kernel stack at `kernel_stack_top`,
+/// where traps from user mode will put it again, and restored from there. It may already
+/// overlap that spot. Interrupts have to be off, anything else landing there would clobber it.
+#[naked]
+pub unsafe extern "C" fn return_to_user(_frame: *const TrapFrame, _kernel_stack_top: usize) -> ! {
+    core::arch::asm!(
+        concat!(
+            r#"
+            // The destination is at or above the frame, so copy from the top down
+            li t0, {SIZE}
+            sub a1, a1, t0
 
-            // Skip sp for... obvious reasons
-            ld gp, 24(sp)
-            ld tp, 32(sp)
-            ld t0, 40(sp)
-            ld t1, 48(sp)
-            ld t2, 56(sp)
-            ld s0, 64(sp)
-            ld s1, 72(sp)
-            ld a0, 80(sp)
-            ld a1, 88(sp)
-            ld a2, 96(sp)
-            ld a3, 104(sp)
-            ld a4, 112(sp)
-            ld a5, 120(sp)
-            ld a6, 128(sp)
-            ld a7, 136(sp)
-            ld s2, 144(sp)
-            ld s3, 152(sp)
-            ld s4, 160(sp)
-            ld s5, 168(sp)
-            ld s6, 176(sp)
-            ld s7, 184(sp)
-            ld s8, 192(sp)
-            ld s9, 200(sp)
-            ld s10, 208(sp)
-            ld s11, 216(sp)
-            ld t3, 224(sp)
-            ld t4, 232(sp)
-            ld t5, 240(sp)
-            ld t6, 248(sp)
+            1:
+            addi t0, t0, -8
+            add t1, a0, t0
+            ld t2, 0(t1)
+            add t1, a1, t0
+            sd t2, 0(t1)
+            bnez t0, 1b
+
+            mv sp, a1
+            "#,
+            trap_restore_asm!()
+        ),
+        SIZE = const core::mem::size_of::<TrapFrame>(),
+        options(noreturn)
+    )
+}
 
-            // Clear any outstanding atomic reservations
-            sc.d zero, zero, 0(sp)
+#[naked]
+#[repr(align(4))]
+pub extern "C" fn int_handler() {
+    unsafe {
+        core::arch::asm!(
+            trap_entry_asm!(),
+            TRAP_FRAME_SIZE = const { -(core::mem::size_of::<TrapFrame>() as isize) },
+            handler = sym handler,
+            options(noreturn)
+        )
+    }
+}
 
-            // Restore `sp`
-            ld sp, 16(sp)
-            
-            // gtfo
-            sret
-            "#,
+#[naked]
+#[repr(align(4))]
+pub extern "C" fn software_int_entry() {
+    unsafe {
+        core::arch::asm!(
+            trap_entry_asm!(),
+            TRAP_FRAME_SIZE = const { -(core::mem::size_of::<TrapFrame>() as isize) },
+            handler = sym software_handler,
+            options(noreturn)
+        )
+    }
+}
+
+#[naked]
+#[repr(align(4))]
+pub extern "C" fn timer_int_entry() {
+    unsafe {
+        core::arch::asm!(
+            trap_entry_asm!(),
             TRAP_FRAME_SIZE = const { -(core::mem::size_of::<TrapFrame>() as isize) },
+            handler = sym timer_handler,
+            options(noreturn)
+        )
+    }
+}
+
+#[naked]
+#[repr(align(4))]
+pub extern "C" fn external_int_entry() {
+    unsafe {
+        core::arch::asm!(
+            trap_entry_asm!(),
+            TRAP_FRAME_SIZE = const { -(core::mem::size_of::<TrapFrame>() as isize) },
+            handler = sym external_handler,
+            options(noreturn)
+        )
+    }
+}
+
+/// `stvec` target in vectored mode, interrupts jump to `BASE + 4 * cause`
+///
+/// Exceptions always land on the first entry, and any cause without a dedicated
+/// stub falls back to `int_handler`, which decodes `scause` itself
+#[naked]
+#[repr(align(256))]
+pub extern "C" fn trap_vector_table() {
+    unsafe {
+        core::arch::asm!(
+            r#"
+            // Every entry has to be exactly 4 bytes
+            .option push
+            .option norvc
+            j {direct}
+            j {software}
+            j {direct}
+            j {direct}
+            j {direct}
+            j {timer}
+            j {direct}
+            j {direct}
+            j {direct}
+            j {external}
+            .option pop
+            "#,
+            direct = sym int_handler,
+            software = sym software_int_entry,
+            timer = sym timer_int_entry,
+            external = sym external_int_entry,
             options(noreturn)
         )
     }
@@ -293,16 +576,82 @@ pub extern "C" fn int_handler() {
 
 #[no_mangle]
 #[repr(align(4))]
-pub extern "C" fn handler() {
-    let int_vec = interrupt_vector();
+pub extern "C" fn handler(frame: &mut TrapFrame) {
+    let user_trap = {
+        let _nesting = Nesting::enter();
+        let int_vec = interrupt_vector();
+
+        match int_vec {
+            (true, code) => exception(code, frame),
+            (false, code) => {
+                interrupt(code);
+                UserTrap::None
+            }
+        }
+    };
+
+    // Both only once the trap is unwound, since the thread may block or never come back
+    match user_trap {
+        UserTrap::None => {},
+        UserTrap::Fault(code) => crate::user::kill_current(code),
+        UserTrap::Syscall => {
+            unsafe { core::arch::asm!("csrsi sstatus, 2") };
+            crate::syscall::dispatch(frame);
+            unsafe { core::arch::asm!("csrci sstatus, 2") };
+        }
+    }
 
-    match int_vec {
-        (true, code) => exception(code),
-        (false, code) => interrupt(code)
+    leave(frame);
+}
+
+/// Everything that happens on the way out of the outermost trap
+fn leave(frame: &TrapFrame) {
+    // Threads of an exiting process never make it back to user mode
+    if frame.from_user() {
+        crate::process::check_exiting();
+    }
+
+    // Code that had interrupts off, like a user access fixup under a lock, mustn't be switched away from
+    if frame.interrupts_were_enabled() {
+        crate::thread::scheduler::preempt_if_needed();
     }
 }
 
-fn exception(code: u64) {
+/// What's left to do for a trap from user mode once the handler is done
+enum UserTrap {
+    None,
+    /// Kill the thread, or its whole process
+    Fault(u64),
+    /// Run the system call in the frame
+    Syscall,
+}
+
+fn exception(code: u64, frame: &mut TrapFrame) -> UserTrap {
+    // The FPU is left off after a thread switch, and turned on by its first FP instruction
+    if code == 2 && crate::thread::fp::handle_trap(frame.sstatus) {
+        return UserTrap::None;
+    }
+
+    if frame.from_user() {
+        // Environment call from U-mode
+        if code == 8 {
+            return UserTrap::Syscall;
+        }
+
+        crate::user::log_fault(code, frame);
+
+        return UserTrap::Fault(code);
+    }
+
+    // Load and store faults on user memory are expected in the user copy routines
+    if matches!(code, 5 | 7 | 13 | 15) {
+        if let Some(fixup) = crate::user::access::fixup(frame.sepc) {
+            frame.sepc = fixup;
+
+            return UserTrap::None;
+        }
+    }
+
     match code {
         0 => log::error!("Instruction address misaligned"),
         1 => log::error!("Instruction access fault"),
@@ -321,80 +670,75 @@ fn exception(code: u64) {
 }
 
 fn interrupt(code: u64) {
-    use core::sync::atomic::Ordering;
-
     match code {
-        1 => {
-            //ipi
-            let id = crate::HART_ID.load(Ordering::Relaxed);
-            let mswi_base = crate::MSWI.load(Ordering::Relaxed);
-            unsafe {
-                *mswi_base.add(id) = 0;
-            }
-
-            log::info!("IPI occured, targeting id: {}", id);
-        },
-        5 => {
-            //timer interrupt
-            super::timing::WAIT.store(false, Ordering::Relaxed);
-        },
-        9 => {
-            //plic interrupt
-            plic_int()
-        },
+        1 => software_interrupt(),
+        5 => timer_interrupt(),
+        9 => external_interrupt(),
         _ => log::error!("Error has occured, handler was called with vector: {:b}", code),
     }
 }
 
-fn plic_int() {
-    let context = crate::current_context();
+extern "C" fn software_handler(frame: &mut TrapFrame) {
+    {
+        let _nesting = Nesting::enter();
 
-    let plic = unsafe {&mut *crate::plic::PLIC_REF};
-    let pot_int = plic.claim(context);
+        software_interrupt()
+    }
 
-    match pot_int {
-        None => log::error!("No interrupt found for plic"),
-        Some(int_claim) => {
-            let int = int_claim.interrupt_id();
+    leave(frame);
+}
 
-            match int {
-                10 => {
-                    uart();
-                },
-                _ => {
-                    log::error!("Unrecognized external interrupt: {}", int);
-                }
-            }
+extern "C" fn timer_handler(frame: &mut TrapFrame) {
+    {
+        let _nesting = Nesting::enter();
 
-            int_claim.complete();
-        }
+        timer_interrupt()
     }
+
+    leave(frame);
 }
 
-fn uart() {
-    let my_uart = crate::uart::Uart16550::new(0x1000_0000 as *const u8);
-    
-    let character = my_uart.read();
-    match character {
-        8 => {
-            my_uart.write(8);
-            my_uart.write(b' ');
-            my_uart.write(8);
-        },
-        10 | 13 => {
-            my_uart.write(b'\n');
-        },
-        _ => {
-            crate::log_print!("{}", character as char);
-        },
+extern "C" fn external_handler(frame: &mut TrapFrame) {
+    {
+        let _nesting = Nesting::enter();
+
+        external_interrupt()
+    }
+
+    leave(frame);
+}
+
+fn software_interrupt() {
+    latency::record();
+
+    // Drained either way, an IPI can arrive while a measurement's interrupt is pending
+    crate::smp::ipi::handle()
+}
+
+fn timer_interrupt() {
+    super::timing::timer::handle_interrupt()
+}
+
+fn external_interrupt() {
+    irq::dispatch()
+}
+
+pub fn uart(my_uart: &crate::uart::Uart16550) {
+    while let Some(character) = my_uart.try_read() {
+        crate::io::console::receive(character);
+    }
+
+    if my_uart.data_empty() {
+        crate::io::console::transmit_ready();
     }
 }
 
 #[repr(C)]
+#[derive(Debug, Default, Clone)]
 pub struct GeneralRegisters {
     pub ra: usize, // trapframe offset 8
-    pub gp: usize, // trapframe offset 16
-    pub sp: usize, // trapframe offset 24
+    pub sp: usize, // trapframe offset 16
+    pub gp: usize, // trapframe offset 24
     pub tp: usize, // trapframe offset 32
     pub t0: usize, // trapframe offset 40
     pub t1: usize, // trapframe offset 48
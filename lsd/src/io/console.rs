@@ -0,0 +1,179 @@
+//! Buffered console input, filled from the UART interrupt and drained by whoever reads it,
+//! and interrupt-driven output for async writers
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicPtr, Ordering};
+use core::task::{Context, Poll, Waker};
+
+use crate::sync::{IrqMutex, WaitQueue};
+use crate::uart::Uart16550;
+
+/// Bytes beyond this are dropped until a reader catches up
+const RX_CAPACITY: usize = 256;
+
+static UART: AtomicPtr<Uart16550> = AtomicPtr::new(core::ptr::null_mut());
+
+static RX: IrqMutex<VecDeque<u8>> = IrqMutex::new(VecDeque::new());
+static READERS: WaitQueue = WaitQueue::new();
+static ASYNC_READERS: IrqMutex<Vec<Waker>> = IrqMutex::new(Vec::new());
+static ASYNC_WRITERS: IrqMutex<Vec<Waker>> = IrqMutex::new(Vec::new());
+
+/// Sets the UART async writes go to, its interrupt has to end up in `interrupts::uart`
+pub fn init(uart: &'static Uart16550) {
+    UART.store(uart as *const Uart16550 as *mut Uart16550, Ordering::Release);
+}
+
+fn uart() -> &'static Uart16550 {
+    let uart = UART.load(Ordering::Acquire);
+
+    assert!(!uart.is_null(), "Console UART isn't set up");
+
+    unsafe { &*uart }
+}
+
+/// Queues a received byte and wakes a reader, called from the UART interrupt
+pub fn receive(byte: u8) {
+    {
+        let mut rx = RX.lock();
+
+        if rx.len() >= RX_CAPACITY {
+            return;
+        }
+
+        rx.push_back(byte);
+    }
+
+    READERS.notify_one();
+
+    for waker in core::mem::take(&mut *ASYNC_READERS.lock()) {
+        waker.wake();
+    }
+}
+
+/// Wakes async writers once the transmitter has room, called from the UART interrupt
+pub fn transmit_ready() {
+    let writers = {
+        let mut writers = ASYNC_WRITERS.lock();
+
+        if writers.is_empty() {
+            return;
+        }
+
+        // Stays off until a writer finds the transmitter full again. Writers turn it on under
+        // the same lock, so this can't undo an enable that came with a fresh registration.
+        uart().set_tx_int(false);
+
+        core::mem::take(&mut *writers)
+    };
+
+    for waker in writers {
+        waker.wake();
+    }
+}
+
+pub fn try_read_byte() -> Option<u8> {
+    RX.lock().pop_front()
+}
+
+/// Blocks the current thread until a byte comes in
+pub fn read_byte() -> u8 {
+    let mut byte = None;
+
+    READERS.wait_until(|| {
+        byte = try_read_byte();
+        byte.is_some()
+    });
+
+    byte.unwrap()
+}
+
+pub struct ReadByte;
+
+/// Completes with the next received byte
+pub fn read_byte_async() -> ReadByte {
+    ReadByte
+}
+
+impl Future for ReadByte {
+    type Output = u8;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<u8> {
+        if let Some(byte) = try_read_byte() {
+            return Poll::Ready(byte);
+        }
+
+        register(&mut ASYNC_READERS.lock(), cx.waker());
+
+        // A byte that came in before the waker was registered wouldn't have woken it
+        match try_read_byte() {
+            Some(byte) => Poll::Ready(byte),
+            None => Poll::Pending,
+        }
+    }
+}
+
+// A task polled again before it was woken is already in the list
+fn register(wakers: &mut Vec<Waker>, waker: &Waker) {
+    if !wakers.iter().any(|registered| registered.will_wake(waker)) {
+        wakers.push(waker.clone());
+    }
+}
+
+/// Fills `buf` with received bytes, completing once at least one is in
+pub async fn read(buf: &mut [u8]) -> usize {
+    if buf.is_empty() {
+        return 0;
+    }
+
+    buf[0] = read_byte_async().await;
+
+    let mut count = 1;
+
+    while count < buf.len() {
+        match try_read_byte() {
+            None => break,
+            Some(byte) => buf[count] = byte,
+        }
+
+        count += 1;
+    }
+
+    count
+}
+
+pub struct WriteByte(u8);
+
+impl Future for WriteByte {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let uart = uart();
+
+        if uart.try_write(self.0) {
+            return Poll::Ready(());
+        }
+
+        {
+            let mut writers = ASYNC_WRITERS.lock();
+
+            register(&mut writers, cx.waker());
+            uart.set_tx_int(true);
+        }
+
+        // The transmitter may have drained before the interrupt was enabled
+        match uart.try_write(self.0) {
+            true => Poll::Ready(()),
+            false => Poll::Pending,
+        }
+    }
+}
+
+/// Writes all of `bytes`, waiting on the transmit interrupt whenever the UART is full
+pub async fn write(bytes: &[u8]) {
+    for byte in bytes {
+        WriteByte(*byte).await;
+    }
+}
@@ -1,8 +1,8 @@
 use core::fmt::Write;
 
-use spin::Mutex;
+use crate::sync::IrqMutex;
 
-static UART: Mutex<Uart> = Mutex::new(Uart::new(0x1000_0000));
+static UART: IrqMutex<Uart> = IrqMutex::new(Uart::new(0x1000_0000));
 
 struct Uart(u64);
 
@@ -31,6 +31,18 @@ impl core::fmt::Write for Uart {
     }
 }
 
+/// Writes raw bytes, for output that isn't necessarily UTF-8
+pub fn write_bytes(bytes: &[u8]) {
+    let uart = UART.lock();
+    let ptr = uart.0 as *mut u8;
+
+    for byte in bytes {
+        unsafe {
+            ptr.write_volatile(*byte);
+        }
+    }
+}
+
 pub fn log_fmt(args: core::fmt::Arguments) {
     let mut uart = UART.lock();
     uart.write_fmt(args).expect("Failed to write to UART");
@@ -55,7 +67,18 @@ impl log::Log for Logger {
     }
 
     fn log(&self, record: &log::Record) {
-        log_println!("{}: {}", record.target(), record.args());
+        if let Some(now) = crate::timing::wall_clock_now() {
+            let seconds = now.as_secs() % 86400;
+
+            log_println!("[{:02}:{:02}:{:02}.{:06}] {}: {}", seconds / 3600, seconds / 60 % 60, seconds % 60, now.subsec_micros(), record.target(), record.args());
+        } else if crate::timing::frequency() != 0 {
+            let uptime = crate::timing::uptime();
+
+            log_println!("[{:>5}.{:06}] {}: {}", uptime.as_secs(), uptime.subsec_micros(), record.target(), record.args());
+        } else {
+            // The clock isn't usable until `timing::init` has read the timebase
+            log_println!("{}: {}", record.target(), record.args());
+        }
     }
 
     fn flush(&self) {}
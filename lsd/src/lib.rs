@@ -10,11 +10,14 @@ extern crate alloc;
 
 use core::sync::atomic;
 
+/// Upper bound on the number of harts the kernel keeps per-hart state for
+pub const MAX_HARTS: usize = 8;
+
 #[thread_local]
 pub static HART_ID: atomic::AtomicUsize = atomic::AtomicUsize::new(0);
 
+/// ACLINT supervisor software interrupt device, null if the platform doesn't have one
 pub static SSWI: atomic::AtomicPtr<u32> = atomic::AtomicPtr::new(core::ptr::null_mut());
-pub static MSWI: atomic::AtomicPtr<u32> = atomic::AtomicPtr::new(core::ptr::null_mut());
 
 use log::{log, Level};
 
@@ -26,31 +29,57 @@ pub mod volatile;
 pub mod mem;
 pub mod utils;
 pub mod drivers;
+pub mod sync;
+pub mod idle;
+pub mod sbi;
+pub mod power;
+pub mod smp;
+pub mod percpu;
+pub mod shell;
+pub mod thread;
+pub mod executor;
+pub mod user;
+pub mod syscall;
+pub mod process;
 
 pub use drivers::*;
 
 pub fn reach_loop(msg: &str) {
     log::info!("{}", msg);
-    loop {
-        crate::wfi();
-    }
-}
 
-pub fn init_tp() {
-    use utils::linker::{__tdata_start, __tdata_end};
+    idle::idle_loop()
+}
 
-    let start = unsafe { core::ptr::addr_of!(__tdata_start).cast_mut() };
-    let _end = unsafe { core::ptr::addr_of!(__tdata_end).cast_mut() };
+/// Points `tp` at a fresh thread local block for the current hart, needs the heap
+pub fn init_tp(hart: usize) {
+    let tp = percpu::alloc(hart);
 
     unsafe {
         core::arch::asm!(
             "mv tp, {}",
-            in(reg) start
+            in(reg) tp
         )
     }
 }
 
+/// The current hart's thread pointer
+pub fn thread_pointer() -> *mut u8 {
+    let tp: *mut u8;
+
+    unsafe {
+        core::arch::asm!(
+            "mv {}, tp",
+            out(reg) tp
+        );
+    }
+
+    tp
+}
+
+/// Stops the current hart for good, interrupts are masked so nothing wakes it back up
 pub fn hcf() -> ! {
+    unsafe { core::arch::asm!("csrci sstatus, 2") };
+
     loop {
         unsafe { core::arch::asm!("wfi") };
     }
@@ -68,10 +97,11 @@ pub fn fail() {
     }
 }
 
+/// Where secondary harts end up once their per-hart init is done
 pub fn core_bootstrap() -> ! {
-    log!(Level::Info, "Core started");
+    log!(Level::Info, "Core {} started", HART_ID.load(atomic::Ordering::Relaxed));
 
-    hcf();
+    idle::idle_loop()
 }
 
 pub trait Compat {
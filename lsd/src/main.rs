@@ -13,12 +13,16 @@ extern "C" fn kmain(hartid: usize, devicetree_ptr: *const u8) -> ! {
     
     mem::init(devicetree_ptr);
 
-    init_tp();
+    init_tp(hartid);
     HART_ID.store(hartid, core::sync::atomic::Ordering::Relaxed);
     io::logger::init();
-    syscon_rs::init(devicetree_ptr);
+    smp::init(devicetree_ptr);
+    sbi::init();
+    power::init(devicetree_ptr);
     timing::init(devicetree_ptr);
-    plic::init(devicetree_ptr, current_context()..current_context() + 1);
+    interrupts::irq::init_controller(devicetree_ptr);
+    goldfish_rtc::init(devicetree_ptr);
+    user::init(devicetree_ptr);
 
     let fdt: fdt::Fdt;
     unsafe {
@@ -26,20 +30,26 @@ extern "C" fn kmain(hartid: usize, devicetree_ptr: *const u8) -> ! {
     }
 
     let uart_node = fdt.find_compatible(uart::Uart16550::compatible()).expect("Failed to find Uart");
-    let uart_int = uart_node.property("interrupts").unwrap().as_usize().unwrap();
     let uart_reg = uart_node.reg().unwrap().next().unwrap();
     let uart = unsafe {&*(uart_reg.starting_address.cast_mut() as *mut uart::Uart16550)};
 
     uart.init();
     uart.set_int();
+    io::console::init(uart);
 
-    let plic_ref = unsafe {&mut *plic::PLIC_REF};
-
-    plic_ref.set_context_threshold(current_context(), 0);
-    plic_ref.set_interrupt_priority(uart_int, 7);
-    plic_ref.enable_interrupt(current_context(), uart_int);
+    interrupts::irq::init_hart();
+    interrupts::irq::register_node(uart_node, 7, move |_| interrupts::uart(uart)).expect("Failed to register Uart interrupt");
 
     interrupts::init();
+    idle::init_hart();
+    thread::init_hart();
+
+    // Only when asked for, it takes over the software interrupt for a while
+    if fdt.chosen().bootargs().unwrap_or("").split_whitespace().any(|arg| arg == "latency") {
+        interrupts::latency::report(64);
+    }
+
+    smp::start_secondaries();
 
     for node in fdt.all_nodes() {
         match node.name.contains("virtio") {
@@ -61,13 +71,22 @@ extern "C" fn kmain(hartid: usize, devicetree_ptr: *const u8) -> ! {
         }
     }
 
-    hcf();
+    executor::init();
+
+    match user::start_init(&["init"]) {
+        Ok(process) => log::info!("Started init as pid {}", process.pid()),
+        Err(user::InitError::NoImage) => {},
+        Err(err) => log::error!("Failed to start init: {:?}", err),
+    }
+
+    shell::spawn();
+    idle::idle_loop()
 }
 
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
     log!(Level::Error, "{}", info);
-    lsd::hcf()
+    lsd::power::on_panic()
 }
 
 #[naked]
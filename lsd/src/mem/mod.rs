@@ -5,6 +5,7 @@ use spin::Mutex;
 use linked_list::LinkedListAllocator;
 
 use crate::LLVec;
+use crate::sync::{IrqMutex, IrqMutexGuard};
 
 #[global_allocator]
 static ALLOCATOR: Locked<LinkedListAllocator> = Locked::new(LinkedListAllocator::new());
@@ -84,17 +85,17 @@ pub fn memory_map(devicetree_ptr: *const u8) {
 }
 
 pub struct Locked<A> {
-    inner: spin::Mutex<A>,
+    inner: IrqMutex<A>,
 }
 
 impl<A> Locked<A> {
     pub const fn new(inner: A) -> Self {
         Locked {
-            inner: spin::Mutex::new(inner),
+            inner: IrqMutex::new(inner),
         }
     }
 
-    pub fn lock(&self) -> spin::MutexGuard<A> {
+    pub fn lock(&self) -> IrqMutexGuard<A> {
         self.inner.lock()
     }
 }
@@ -0,0 +1,191 @@
+//! Page tables for user programs
+//!
+//! Every address space gets its own root table, with the kernel's root entries copied in so the
+//! kernel stays mapped whichever one is active. Only the user region is private.
+
+use alloc::alloc::{alloc_zeroed, dealloc, Layout};
+use alloc::collections::BTreeMap;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::entries::{Entry, EntryFlags};
+use super::mapping::{Mapper, MappingError};
+use super::pagetable::{PageTable, PageTableAlloc};
+use super::physical_addr::PhyscialAddress;
+use super::virtual_addr::VirtualAddress;
+use super::PageSize;
+use crate::control_registers::{Satp, SatpState};
+use crate::sync::IrqMutex;
+use crate::user;
+
+const PAGE_SIZE: usize = PageSize::Small as usize;
+const FRAME_LAYOUT: Layout = unsafe { Layout::from_size_align_unchecked(PAGE_SIZE, PAGE_SIZE) };
+
+pub struct AddressSpace {
+    mapper: IrqMutex<Mapper>,
+    satp: usize,
+    /// Frames backing the user region by the page they're mapped at, owned by the address space
+    frames: IrqMutex<BTreeMap<usize, usize>>,
+    mmap_next: AtomicUsize,
+}
+
+impl AddressSpace {
+    /// An address space with nothing but the kernel mapped
+    pub fn new() -> Self {
+        let mut allocator = PageTableAlloc::new();
+        let root_ptr = allocator.alloc();
+        let root = unsafe {&mut *root_ptr};
+
+        let paging_type = unsafe {crate::mem::PAGING_TYPE};
+
+        {
+            let kernel = super::KERNEL_MAPPER.lock();
+            let kernel_root = kernel.as_ref().expect("Paging isn't set up").root();
+
+            // Shared lower level tables mean later kernel mappings inside these entries show up everywhere
+            for (index, entry) in kernel_root.0.iter().enumerate() {
+                if !is_user_entry(index) {
+                    root[index] = *entry;
+                }
+            }
+        }
+
+        let satp = SatpState::new(paging_type, 0, root.ppn()).bits() as usize;
+
+        Self {
+            mapper: IrqMutex::new(Mapper::new(root, allocator, paging_type)),
+            satp,
+            frames: IrqMutex::new(BTreeMap::new()),
+            mmap_next: AtomicUsize::new(user::MMAP_START),
+        }
+    }
+
+    pub fn satp(&self) -> usize {
+        self.satp
+    }
+
+    /// Switches the current hart to this address space
+    pub fn activate(&self) {
+        switch_to(self.satp);
+    }
+
+    /// Maps fresh zeroed frames over `[addr, addr + len)`, which has to be page aligned and in the user region
+    pub fn map_zeroed(&self, addr: usize, len: usize, flags: EntryFlags) -> Result<(), MappingError> {
+        if addr % PAGE_SIZE != 0 || !user::is_user_range(addr, len) {
+            return Err(MappingError::InvalidPermissions);
+        }
+
+        for page in (addr..addr + len).step_by(PAGE_SIZE) {
+            let frame = unsafe { alloc_zeroed(FRAME_LAYOUT) };
+
+            if frame.is_null() {
+                return Err(MappingError::Unknown);
+            }
+
+            self.map_frame(page, frame as usize, flags)?;
+        }
+
+        Ok(())
+    }
+
+    /// Maps an owned frame at `page`, replacing whatever was there
+    ///
+    /// The frame has to come from the kernel heap, it's freed along with the address space
+    pub fn map_frame(&self, page: usize, frame: usize, flags: EntryFlags) -> Result<(), MappingError> {
+        let flags = flags | EntryFlags::VALID | EntryFlags::USER_ACCESSIBLE | EntryFlags::ACCESSED | EntryFlags::DIRTY;
+
+        self.mapper.lock().recursive_map(PhyscialAddress::new(frame as u64), VirtualAddress::new(page as u64), flags, PageSize::Small)?;
+
+        if let Some(old) = self.frames.lock().insert(page, frame) {
+            if old != frame {
+                unsafe { dealloc(old as *mut u8, FRAME_LAYOUT) };
+            }
+        }
+
+        crate::smp::tlb::flush_local(page, PAGE_SIZE);
+
+        Ok(())
+    }
+
+    /// The physical address `virt` is mapped to
+    pub fn translate(&self, virt: usize) -> Option<usize> {
+        self.mapper.lock().translate(VirtualAddress::new(virt as u64)).map(|phys| phys.as_u64() as usize)
+    }
+
+    /// Hands out `len` bytes of the mmap area, nothing is mapped there yet
+    pub fn reserve(&self, len: usize) -> Option<usize> {
+        let addr = self.mmap_next.fetch_add(len, Ordering::Relaxed);
+
+        match user::is_user_range(addr, len) {
+            true => Some(addr),
+            false => None,
+        }
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        // Never free tables a hart could still be walking
+        if Satp::read() == self.satp {
+            switch_to(super::kernel_satp());
+        }
+
+        for frame in self.frames.get_mut().values() {
+            unsafe { dealloc(*frame as *mut u8, FRAME_LAYOUT) };
+        }
+
+        let mapper = self.mapper.get_mut();
+        let root = mapper.root() as *const PageTable as *mut PageTable;
+
+        // Everything outside the user entries belongs to the kernel's tables
+        for index in (0..512).filter(|index| is_user_entry(*index)) {
+            let entry = unsafe {(*root)[index]};
+
+            if is_table(entry) {
+                free_table(&mapper.alloc, entry.addr(), 1);
+            }
+        }
+
+        mapper.alloc.dealloc(root);
+    }
+}
+
+// Valid without any permission bits means it points at the next level
+fn is_table(entry: Entry) -> bool {
+    entry.has_flag(EntryFlags::VALID) && !entry.has_flag(EntryFlags::READ) && !entry.has_flag(EntryFlags::EXECUTE)
+}
+
+/// Frees `table` and the tables under it, `level` counts how many levels of tables are left below it
+fn free_table(alloc: &PageTableAlloc, table: *mut PageTable, level: usize) {
+    if level > 0 {
+        for index in 0..512 {
+            let entry = unsafe {(*table)[index]};
+
+            if is_table(entry) {
+                free_table(alloc, entry.addr(), level - 1);
+            }
+        }
+    }
+
+    alloc.dealloc(table);
+}
+
+fn is_user_entry(index: usize) -> bool {
+    // Each root entry of an Sv39 table covers 1 GiB
+    let first = user::USER_START >> 30;
+    let last = (user::USER_END - 1) >> 30;
+
+    (first..=last).contains(&index)
+}
+
+/// Loads `satp` on the current hart if it isn't already, nothing uses ASIDs so the whole TLB goes
+pub fn switch_to(satp: usize) {
+    if Satp::read() == satp {
+        return;
+    }
+
+    Satp::write(satp);
+
+    unsafe {
+        core::arch::asm!("sfence.vma zero, zero");
+    }
+}
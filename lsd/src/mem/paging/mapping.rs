@@ -5,6 +5,7 @@ use super::physical_addr::PhyscialAddress;
 use super::virtual_addr::VirtualAddress;
 use super::pagetable::{PageTable, PageTableAlloc};
 use super::entries::{EntryFlags, Entry};
+use crate::smp::tlb::PendingFlush;
 
 pub struct Mapper {
     root: &'static mut PageTable,
@@ -123,6 +124,42 @@ impl Mapper {
         Ok(())
     }
 
+    /// Walks the tables for `virt` without changing them, None if nothing is mapped there
+    pub fn translate(&self, virt: VirtualAddress) -> Option<PhyscialAddress> {
+        let lo_depth = match self.paging_type {
+            PagingType::Sv39 => 2,
+            PagingType::Sv48 => 1,
+            _ => return None
+        };
+
+        let sections = virt.sections();
+        let mut table = &*self.root as *const PageTable;
+
+        for depth in lo_depth..=4 {
+            let entry = unsafe {(*table)[sections[depth] as usize]};
+
+            if !entry.has_flag(EntryFlags::VALID) {
+                return None;
+            }
+
+            // Leaves can be found above the last level, as mega and giga pages
+            if entry.has_flag(EntryFlags::READ) || entry.has_flag(EntryFlags::EXECUTE) {
+                let page_size = 1u64 << (12 + 9 * (4 - depth));
+                let base = entry.addr() as u64;
+
+                return Some(PhyscialAddress::new(base + (virt.as_u64() & (page_size - 1))));
+            }
+
+            table = entry.addr();
+        }
+
+        None
+    }
+
+    pub fn root(&self) -> &PageTable {
+        self.root
+    }
+
     pub fn page_check(&mut self, entry: Entry) -> Option<*mut PageTable> {
         // Invalid flag sets:
         // W
@@ -139,7 +176,9 @@ impl Mapper {
         };
     }
     
-    pub fn unmap(&mut self, virt: VirtualAddress, page_size: PageSize) -> Result<(), MappingError> {
+    /// Removes the mapping at `virt`, other harts keep the old translation until the returned
+    /// flush is done, which has to wait until whatever lock guards this mapper is dropped
+    pub fn unmap(&mut self, virt: VirtualAddress, page_size: PageSize) -> Result<PendingFlush, MappingError> {
         let lo_depth = match self.paging_type {
             PagingType::Sv39 => 2,
             PagingType::Sv48 => 1,
@@ -181,7 +220,7 @@ impl Mapper {
 
         unsafe {(*src_table)[sections[hi_depth] as usize] = entry};
 
-        Ok(())
+        Ok(PendingFlush::new(virt.as_u64() as usize, page_size as usize))
     }
 }
 
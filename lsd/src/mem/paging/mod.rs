@@ -3,9 +3,22 @@ pub mod physical_addr;
 pub mod virtual_addr;
 pub mod mapping;
 pub mod entries;
+pub mod address_space;
+
+pub use address_space::AddressSpace;
 
 const PAGE_SIZE: usize = 4096;
 
+/// The mapper for the kernel's own page tables, None until `init` has run
+pub static KERNEL_MAPPER: crate::sync::IrqMutex<Option<mapping::Mapper>> = crate::sync::IrqMutex::new(None);
+
+static KERNEL_SATP: core::sync::atomic::AtomicUsize = core::sync::atomic::AtomicUsize::new(0);
+
+/// The `satp` value for the kernel's own page tables, what every kernel thread runs with
+pub fn kernel_satp() -> usize {
+    KERNEL_SATP.load(core::sync::atomic::Ordering::Relaxed)
+}
+
 pub fn init() {
     use crate::mem;
 
@@ -96,9 +109,10 @@ pub fn init() {
         let phys = physical_addr::PhyscialAddress::new(addr);
         let virt = virtual_addr::VirtualAddress::new(addr);
 
-        //make the PTE accessed, dirty, readable, writable, and valid
+        //make the PTE accessed, dirty, executable, readable, writable, and valid
+        //never user accessible, every address space shares these entries
         use entries::EntryFlags;
-        let flags = EntryFlags::ACCESSED | EntryFlags::DIRTY | EntryFlags::READ | EntryFlags::WRITE | EntryFlags::VALID | EntryFlags::EXECUTE | EntryFlags::USER_ACCESSIBLE;
+        let flags = EntryFlags::ACCESSED | EntryFlags::DIRTY | EntryFlags::READ | EntryFlags::WRITE | EntryFlags::VALID | EntryFlags::EXECUTE;
 
         mapper.recursive_map(phys, virt, flags, PageSize::Small).expect("Failed to map address");
     }
@@ -107,7 +121,10 @@ pub fn init() {
 
     //enable paging
     let state = SatpState::new(PagingType::Sv39, 0, unsafe {&*table_ptr}.ppn());
+    KERNEL_SATP.store(state.bits() as usize, core::sync::atomic::Ordering::Relaxed);
     Satp::write_state(state);
+
+    *KERNEL_MAPPER.lock() = Some(mapper);
 }
 
 pub struct Page([u8; PAGE_SIZE]);
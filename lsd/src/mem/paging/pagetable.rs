@@ -30,7 +30,7 @@ pub struct PageTableAlloc {
     pub pages_used: usize
 }
 
-use alloc::alloc::{dealloc, alloc, Layout};
+use alloc::alloc::{dealloc, alloc_zeroed, Layout};
 
 impl PageTableAlloc {
     pub fn new() -> Self {
@@ -39,7 +39,8 @@ impl PageTableAlloc {
 
     pub fn alloc(&mut self) -> *mut PageTable {
         unsafe {
-            let ptr = alloc(Layout::new::<PageTable>());
+            // Stale heap contents would read as valid entries
+            let ptr = alloc_zeroed(Layout::new::<PageTable>());
 
             ptr as *mut PageTable
         }
@@ -8,6 +8,10 @@ impl VirtualAddress {
         Self(addr)
     }
 
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+
     pub fn to_phys(&self) -> PhyscialAddress {
         PhyscialAddress::new(self.0)
     }
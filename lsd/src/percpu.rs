@@ -0,0 +1,92 @@
+//! Per-hart thread local blocks
+//!
+//! RISC-V uses TLS variant I, so `tp` points straight at the start of the `.tdata` copy and
+//! every `#[thread_local]` lives at a fixed positive offset from it. The kernel's own per-CPU
+//! header sits just below `tp`:
+//!
+//! ```text
+//! block                     tp
+//! | PerCpu (padded) | .tdata copy | .tbss zeroed |
+//! ```
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::utils::linker::{__tdata_start, __tdata_end, __tbss_start, __tbss_end};
+
+/// Matches the alignment of `.tdata` in virt.lds
+pub const TLS_ALIGN: usize = 64;
+
+const HEADER_SIZE: usize = (core::mem::size_of::<PerCpu>() + TLS_ALIGN - 1) & !(TLS_ALIGN - 1);
+
+/// Per-hart data reachable through `tp` without any `#[thread_local]` offsets
+#[repr(C)]
+pub struct PerCpu {
+    pub hart_id: usize,
+    /// Start of the whole allocation, the header included
+    pub block: *mut u8,
+    pub block_size: usize,
+    /// Top of the stack used for traps taken from user mode
+    pub int_stack_top: AtomicUsize,
+}
+
+impl PerCpu {
+    /// The current hart's header, only valid once `tp` points at a block from `alloc`
+    pub fn current() -> &'static PerCpu {
+        unsafe {&*(crate::thread_pointer().sub(HEADER_SIZE) as *const PerCpu)}
+    }
+
+    pub fn int_stack_top(&self) -> *mut u8 {
+        self.int_stack_top.load(Ordering::Relaxed) as *mut u8
+    }
+}
+
+/// Frees a block from `alloc`, given the `tp` it returned
+///
+/// # Safety
+/// No hart may still be using the block
+pub unsafe fn free(tp: *mut u8) {
+    let header = &*(tp.sub(HEADER_SIZE) as *const PerCpu);
+    let layout = alloc::alloc::Layout::from_size_align(header.block_size, TLS_ALIGN).unwrap();
+
+    alloc::alloc::dealloc(header.block, layout);
+}
+
+/// Size of the thread local image, `.tbss` included
+pub fn tls_size() -> usize {
+    unsafe { __tbss_end.as_usize().max(__tdata_end.as_usize()) - __tdata_start.as_usize() }
+}
+
+/// Allocates a block for `hart`, copying `.tdata` and zeroing `.tbss`, and returns the value for its `tp`
+///
+/// The linked image itself is never used directly, so every copy starts from the initial values
+pub fn alloc(hart: usize) -> *mut u8 {
+    let tdata = unsafe { __tdata_start.as_ptr() };
+    let tdata_len = unsafe { __tdata_end.as_usize() - __tdata_start.as_usize() };
+    let tbss_offset = unsafe { __tbss_start.as_usize() - __tdata_start.as_usize() };
+    let size = HEADER_SIZE + tls_size();
+
+    unsafe {
+        let layout = alloc::alloc::Layout::from_size_align(size, TLS_ALIGN).unwrap();
+        let block = alloc::alloc::alloc(layout);
+
+        if block.is_null() {
+            panic!("Out of memory allocating thread locals");
+        }
+
+        let tp = block.add(HEADER_SIZE);
+
+        core::ptr::copy_nonoverlapping(tdata, tp, tdata_len);
+        // Also clears any padding between the two sections
+        core::ptr::write_bytes(tp.add(tdata_len), 0, tls_size() - tdata_len);
+        debug_assert!(tbss_offset >= tdata_len);
+
+        (block as *mut PerCpu).write(PerCpu {
+            hart_id: hart,
+            block,
+            block_size: size,
+            int_stack_top: AtomicUsize::new(0),
+        });
+
+        tp
+    }
+}
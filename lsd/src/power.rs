@@ -0,0 +1,156 @@
+use core::sync::atomic::{AtomicPtr, AtomicU32, AtomicU8, Ordering};
+
+use crate::sbi::{self, srst, Extension};
+
+static POWEROFF: SysconRegister = SysconRegister::new();
+static REBOOT: SysconRegister = SysconRegister::new();
+static PANIC_ACTION: AtomicU8 = AtomicU8::new(PanicAction::Shutdown as u8);
+
+/// Why the system is going down, passed on to the firmware
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    Normal,
+    Failure,
+}
+
+/// What the panic handler does once the panic has been logged
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PanicAction {
+    /// Stop the panicking hart and leave the machine running so it can be inspected
+    Halt,
+    Shutdown,
+    Reboot,
+}
+
+/// A `syscon-poweroff` or `syscon-reboot` register, and what to write to it
+struct SysconRegister {
+    addr: AtomicPtr<u32>,
+    value: AtomicU32,
+}
+
+impl SysconRegister {
+    const fn new() -> Self {
+        Self { addr: AtomicPtr::new(core::ptr::null_mut()), value: AtomicU32::new(0) }
+    }
+
+    fn init(&self, fdt: &fdt::Fdt, compatible: &str) {
+        let node = match fdt.find_compatible(&[compatible]) {
+            None => return,
+            Some(node) => node
+        };
+
+        let register = (|| {
+            let offset = node.property("offset")?.as_usize()?;
+            let value = node.property("value")?.as_usize()? as u32;
+            let regmap = fdt.find_phandle(node.property("regmap")?.as_usize()? as u32)?;
+            let base = regmap.reg()?.next()?.starting_address;
+
+            Some((unsafe { base.add(offset) } as *mut u32, value))
+        })();
+
+        match register {
+            None => log::warn!("Couldn't make sense of the {} node", compatible),
+            Some((addr, value)) => {
+                self.value.store(value, Ordering::Relaxed);
+                self.addr.store(addr, Ordering::Relaxed);
+            }
+        }
+    }
+
+    // Returns if there's no register, or if writing it didn't take the machine down
+    fn write(&self) {
+        let addr = self.addr.load(Ordering::Relaxed);
+
+        if addr.is_null() {
+            return;
+        }
+
+        unsafe { addr.write_volatile(self.value.load(Ordering::Relaxed)) };
+
+        // Give the write a moment to land before giving up on it, spinning since this can be
+        // reached from the panic handler with interrupts off
+        let start = crate::timing::ticks();
+        let wait = crate::timing::duration_to_ticks(core::time::Duration::from_millis(100));
+
+        while crate::timing::ticks() - start < wait {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Sets up the syscon fallback and reads `panic=halt|shutdown|reboot` from the bootargs
+pub fn init(devicetree_ptr: *const u8) {
+    let fdt = unsafe {fdt::Fdt::from_ptr(devicetree_ptr).expect("Failed to get fdt")};
+
+    POWEROFF.init(&fdt, "syscon-poweroff");
+    REBOOT.init(&fdt, "syscon-reboot");
+    let bootargs = fdt.chosen().bootargs().unwrap_or("");
+
+    for arg in bootargs.split_whitespace() {
+        match arg {
+            "panic=halt" => set_panic_action(PanicAction::Halt),
+            "panic=shutdown" => set_panic_action(PanicAction::Shutdown),
+            "panic=reboot" => set_panic_action(PanicAction::Reboot),
+            _ => {}
+        }
+    }
+}
+
+pub fn set_panic_action(action: PanicAction) {
+    PANIC_ACTION.store(action as u8, Ordering::Relaxed);
+}
+
+pub fn panic_action() -> PanicAction {
+    match PANIC_ACTION.load(Ordering::Relaxed) {
+        0 => PanicAction::Halt,
+        1 => PanicAction::Shutdown,
+        _ => PanicAction::Reboot,
+    }
+}
+
+pub fn shutdown(reason: Reason) -> ! {
+    log::info!("Shutting down ({:?})", reason);
+
+    reset(srst::ResetType::Shutdown, reason);
+
+    POWEROFF.write();
+
+    log::error!("No way to power off, halting instead");
+    crate::hcf()
+}
+
+pub fn reboot(reason: Reason) -> ! {
+    log::info!("Rebooting ({:?})", reason);
+
+    reset(srst::ResetType::ColdReboot, reason);
+
+    REBOOT.write();
+
+    log::error!("No way to reboot, halting instead");
+    crate::hcf()
+}
+
+/// Carries out the configured `PanicAction`, the panic should already be logged
+pub fn on_panic() -> ! {
+    match panic_action() {
+        PanicAction::Halt => crate::hcf(),
+        PanicAction::Shutdown => shutdown(Reason::Failure),
+        PanicAction::Reboot => reboot(Reason::Failure),
+    }
+}
+
+// Only returns if SRST is missing or the firmware refused
+fn reset(reset: srst::ResetType, reason: Reason) {
+    if !sbi::available(Extension::Srst) {
+        return;
+    }
+
+    let reason = match reason {
+        Reason::Normal => srst::ResetReason::NoReason,
+        Reason::Failure => srst::ResetReason::SystemFailure,
+    };
+
+    let err = srst::system_reset(reset, reason);
+    log::warn!("SBI system reset failed: {:?}", err);
+}
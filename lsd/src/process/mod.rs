@@ -0,0 +1,275 @@
+//! Processes: a user address space, the threads running in it, and the bookkeeping around
+//! their parent waiting for them to finish
+
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::mem::paging::AddressSpace;
+use crate::sync::{IrqMutex, WaitQueue};
+use crate::thread::{self, Thread};
+use crate::user::{self, elf::Program};
+
+// 0 is what threads outside any process report
+static NEXT_PID: AtomicUsize = AtomicUsize::new(1);
+
+static PROCESSES: IrqMutex<BTreeMap<Pid, Arc<Process>>> = IrqMutex::new(BTreeMap::new());
+
+/// Parents waiting on a child to exit
+static CHILD_EXITED: WaitQueue = WaitQueue::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Pid(usize);
+
+impl Pid {
+    pub fn from_usize(pid: usize) -> Self {
+        Self(pid)
+    }
+
+    pub fn as_usize(&self) -> usize {
+        self.0
+    }
+}
+
+impl core::fmt::Display for Pid {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::Display::fmt(&self.0, f)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    Exited(isize),
+    Killed,
+    /// Took a fault from user mode, with its `scause`
+    Faulted(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessError {
+    NoSuchProcess,
+    NoChildren,
+    /// The waiting process started exiting
+    Interrupted,
+}
+
+pub struct Process {
+    pid: Pid,
+    name: String,
+    parent: IrqMutex<Option<Pid>>,
+    /// Taken once the last thread is gone, dropping the last reference frees it
+    address_space: IrqMutex<Option<Arc<AddressSpace>>>,
+    threads: IrqMutex<Vec<Arc<Thread>>>,
+    live_threads: AtomicUsize,
+    /// Set once any thread exits or the process is killed, the others follow on their way back to user mode
+    exiting: AtomicBool,
+    /// The first reason given for exiting wins
+    status: IrqMutex<Option<ExitStatus>>,
+    zombie: AtomicBool,
+}
+
+impl Process {
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn parent(&self) -> Option<Pid> {
+        *self.parent.lock()
+    }
+
+    /// What the process exited with, None while it's still running
+    pub fn exit_status(&self) -> Option<ExitStatus> {
+        match self.is_zombie() {
+            true => *self.status.lock(),
+            false => None,
+        }
+    }
+
+    pub fn is_zombie(&self) -> bool {
+        self.zombie.load(Ordering::Acquire)
+    }
+
+    pub fn is_exiting(&self) -> bool {
+        self.exiting.load(Ordering::Acquire)
+    }
+
+    pub fn thread_count(&self) -> usize {
+        self.live_threads.load(Ordering::Relaxed)
+    }
+
+    fn start_exit(&self, status: ExitStatus) {
+        self.status.lock().get_or_insert(status);
+        self.exiting.store(true, Ordering::Release);
+
+        // Blocked threads have to get back to a syscall return to notice
+        for thread in self.threads.lock().iter() {
+            thread::unblock(thread);
+        }
+    }
+
+    // Run by the last thread out
+    fn finish(&self) {
+        drop(self.address_space.lock().take());
+        self.threads.lock().clear();
+
+        let mut processes = PROCESSES.lock();
+
+        // Orphans are reaped as soon as they exit, nobody is going to wait on them
+        for process in processes.values() {
+            if process.parent() == Some(self.pid) {
+                *process.parent.lock() = None;
+            }
+        }
+
+        processes.retain(|_, process| !(process.parent().is_none() && process.is_zombie()));
+
+        self.zombie.store(true, Ordering::Release);
+
+        if self.parent().is_none() {
+            processes.remove(&self.pid);
+        }
+
+        drop(processes);
+
+        CHILD_EXITED.notify_all();
+    }
+}
+
+/// Starts a process running `program`, as a child of the current process if there is one
+pub fn spawn(name: &str, program: Program) -> Arc<Process> {
+    let process = Arc::new(Process {
+        pid: Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed)),
+        name: name.to_string(),
+        parent: IrqMutex::new(current().map(|parent| parent.pid)),
+        address_space: IrqMutex::new(Some(program.address_space.clone())),
+        threads: IrqMutex::new(Vec::new()),
+        live_threads: AtomicUsize::new(1),
+        exiting: AtomicBool::new(false),
+        status: IrqMutex::new(None),
+        zombie: AtomicBool::new(false),
+    });
+
+    PROCESSES.lock().insert(process.pid, process.clone());
+
+    let pid = process.pid;
+
+    // Held until the thread is on the list, so an exit starting meanwhile can't miss waking it
+    let mut threads = process.threads.lock();
+    let handle = thread::spawn(name, move || {
+        let current = thread::current();
+        current.set_process(pid);
+        current.set_address_space(program.address_space);
+        drop(current);
+
+        user::enter(program.entry, program.stack_pointer)
+    });
+
+    threads.push(handle.thread().clone());
+    drop(threads);
+
+    process
+}
+
+pub fn get(pid: Pid) -> Option<Arc<Process>> {
+    PROCESSES.lock().get(&pid).cloned()
+}
+
+/// The process the current thread belongs to, None for kernel threads
+pub fn current() -> Option<Arc<Process>> {
+    thread::try_current().and_then(|thread| thread.process()).and_then(get)
+}
+
+/// Every process that hasn't been reaped yet
+pub fn list() -> Vec<Arc<Process>> {
+    PROCESSES.lock().values().cloned().collect()
+}
+
+/// Whether the current thread's process is on its way out, blocking syscalls give up once it is
+pub fn current_exiting() -> bool {
+    current().map_or(false, |process| process.is_exiting())
+}
+
+/// Ends the whole current process, or just the thread for a thread outside any process
+pub fn exit(status: ExitStatus) -> ! {
+    if let Some(process) = current() {
+        process.start_exit(status);
+    }
+
+    exit_thread()
+}
+
+/// Ends the current thread, finishing its process if it was the last one
+pub fn exit_thread() -> ! {
+    if let Some(process) = current() {
+        if process.live_threads.fetch_sub(1, Ordering::AcqRel) == 1 {
+            process.finish();
+        }
+    }
+
+    thread::exit()
+}
+
+/// Makes a process exit, its threads go once they're next on their way to user mode
+pub fn kill(pid: Pid) -> Result<(), ProcessError> {
+    let process = get(pid).ok_or(ProcessError::NoSuchProcess)?;
+
+    if !process.is_zombie() {
+        process.start_exit(ExitStatus::Killed);
+    }
+
+    Ok(())
+}
+
+/// Called on every return to user mode, so the threads of an exiting process don't get back there
+pub(crate) fn check_exiting() {
+    if let Some(process) = current() {
+        if process.is_exiting() {
+            drop(process);
+            exit_thread();
+        }
+    }
+}
+
+/// Waits for a child of the current process to exit and reaps it, any child if `pid` is None
+pub fn wait(pid: Option<Pid>) -> Result<(Pid, ExitStatus), ProcessError> {
+    let parent = current().map(|process| process.pid);
+    let mut reaped = None;
+
+    CHILD_EXITED.wait_until(|| {
+        // Killing the process unblocks this thread, it has to get back out to be reaped
+        if current_exiting() {
+            reaped = Some(Err(ProcessError::Interrupted));
+            return true;
+        }
+
+        let mut processes = PROCESSES.lock();
+
+        let mut children = processes.values()
+            .filter(|process| process.parent() == parent && parent.is_some())
+            .filter(|process| pid.map_or(true, |pid| process.pid == pid))
+            .peekable();
+
+        if children.peek().is_none() {
+            reaped = Some(Err(ProcessError::NoChildren));
+            return true;
+        }
+
+        let zombie = children.find(|process| process.is_zombie()).map(|process| process.pid);
+
+        match zombie.and_then(|zombie| processes.remove(&zombie)) {
+            None => false,
+            Some(zombie) => {
+                reaped = Some(Ok((zombie.pid, zombie.exit_status().unwrap_or(ExitStatus::Killed))));
+                true
+            }
+        }
+    });
+
+    reaped.unwrap()
+}
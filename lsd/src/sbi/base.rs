@@ -0,0 +1,62 @@
+use super::ecall;
+
+pub const EXTENSION_ID: usize = 0x10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpecVersion {
+    pub major: usize,
+    pub minor: usize,
+}
+
+pub fn spec_version() -> SpecVersion {
+    let version = call(0);
+
+    SpecVersion { major: (version >> 24) & 0x7f, minor: version & 0xff_ffff }
+}
+
+pub fn impl_id() -> usize {
+    call(1)
+}
+
+pub fn impl_version() -> usize {
+    call(2)
+}
+
+/// Returns true if the firmware implements the extension with the given id
+pub fn probe_extension(id: usize) -> bool {
+    unsafe { ecall(EXTENSION_ID, 3, [id, 0, 0, 0, 0, 0]).map(|value| value != 0).unwrap_or(false) }
+}
+
+pub fn mvendorid() -> usize {
+    call(4)
+}
+
+pub fn marchid() -> usize {
+    call(5)
+}
+
+pub fn mimpid() -> usize {
+    call(6)
+}
+
+pub fn impl_name(id: usize) -> &'static str {
+    match id {
+        0 => "Berkeley Boot Loader",
+        1 => "OpenSBI",
+        2 => "Xvisor",
+        3 => "KVM",
+        4 => "RustSBI",
+        5 => "Diosix",
+        6 => "Coffer",
+        7 => "Xen Project",
+        8 => "PolarFire Hart Software Services",
+        9 => "coreboot",
+        10 => "oreboot",
+        _ => "Unknown",
+    }
+}
+
+// Base extension calls can't fail
+fn call(function: usize) -> usize {
+    unsafe { ecall(EXTENSION_ID, function, [0; 6]).unwrap_or(0) }
+}
@@ -0,0 +1,16 @@
+use super::{ecall, SbiResult};
+
+pub const EXTENSION_ID: usize = 0x43505043;
+
+/// Returns the width in bits of the CPPC register `register`, or zero if it isn't implemented
+pub fn probe(register: usize) -> SbiResult<usize> {
+    unsafe { ecall(EXTENSION_ID, 0, [register, 0, 0, 0, 0, 0]) }
+}
+
+pub fn read(register: usize) -> SbiResult<usize> {
+    unsafe { ecall(EXTENSION_ID, 1, [register, 0, 0, 0, 0, 0]) }
+}
+
+pub fn write(register: usize, value: u64) -> SbiResult<()> {
+    unsafe { ecall(EXTENSION_ID, 3, [register, value as usize, 0, 0, 0, 0]).map(drop) }
+}
@@ -0,0 +1,23 @@
+use super::{ecall, SbiResult};
+
+pub const EXTENSION_ID: usize = 0x4442434E;
+
+/// Writes as much of `bytes` as the firmware accepts, returns how many were written
+///
+/// The buffer is passed by physical address, which is fine while the kernel is identity mapped
+pub fn write(bytes: &[u8]) -> SbiResult<usize> {
+    let address = bytes.as_ptr() as usize;
+
+    unsafe { ecall(EXTENSION_ID, 0, [bytes.len(), address, 0, 0, 0, 0]) }
+}
+
+/// Reads whatever is waiting into `bytes`, returns how many were read
+pub fn read(bytes: &mut [u8]) -> SbiResult<usize> {
+    let address = bytes.as_mut_ptr() as usize;
+
+    unsafe { ecall(EXTENSION_ID, 1, [bytes.len(), address, 0, 0, 0, 0]) }
+}
+
+pub fn write_byte(byte: u8) -> SbiResult<()> {
+    unsafe { ecall(EXTENSION_ID, 2, [byte as usize, 0, 0, 0, 0, 0]).map(drop) }
+}
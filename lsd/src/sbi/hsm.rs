@@ -0,0 +1,73 @@
+use super::{ecall, SbiError, SbiResult};
+
+pub const EXTENSION_ID: usize = 0x48534D;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HartStatus {
+    Started,
+    Stopped,
+    StartPending,
+    StopPending,
+    Suspended,
+    SuspendPending,
+    ResumePending,
+}
+
+/// Suspend types defined by the spec, platform specific ones start at 0x1000_0000 and 0x9000_0000
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SuspendType {
+    /// Registers and CSRs are preserved, the call returns like `wfi`
+    Retentive(u32),
+    /// State is lost, the hart resumes at the given address instead of returning
+    NonRetentive(u32),
+}
+
+impl SuspendType {
+    pub const DEFAULT_RETENTIVE: Self = Self::Retentive(0);
+    pub const DEFAULT_NON_RETENTIVE: Self = Self::NonRetentive(0x8000_0000);
+
+    fn value(self) -> usize {
+        match self {
+            Self::Retentive(value) | Self::NonRetentive(value) => value as usize,
+        }
+    }
+}
+
+/// Starts `hart` at the physical address `start` in S-mode, with `opaque` in a1 and its id in a0
+///
+/// # Safety
+/// `start` must be code that can run with the MMU off and no stack
+pub unsafe fn hart_start(hart: usize, start: usize, opaque: usize) -> SbiResult<()> {
+    ecall(EXTENSION_ID, 0, [hart, start, opaque, 0, 0, 0]).map(drop)
+}
+
+/// Stops the current hart, only returns if the firmware refused
+pub fn hart_stop() -> SbiError {
+    match unsafe { ecall(EXTENSION_ID, 1, [0; 6]) } {
+        Ok(_) => SbiError::Failed,
+        Err(err) => err,
+    }
+}
+
+pub fn hart_status(hart: usize) -> SbiResult<HartStatus> {
+    let status = unsafe { ecall(EXTENSION_ID, 2, [hart, 0, 0, 0, 0, 0])? };
+
+    match status {
+        0 => Ok(HartStatus::Started),
+        1 => Ok(HartStatus::Stopped),
+        2 => Ok(HartStatus::StartPending),
+        3 => Ok(HartStatus::StopPending),
+        4 => Ok(HartStatus::Suspended),
+        5 => Ok(HartStatus::SuspendPending),
+        6 => Ok(HartStatus::ResumePending),
+        _ => Err(SbiError::Failed),
+    }
+}
+
+/// Suspends the current hart, retentive suspends return once the hart wakes up
+///
+/// # Safety
+/// For non-retentive suspends `resume` is entered like `hart_start`, with `opaque` in a1
+pub unsafe fn hart_suspend(suspend: SuspendType, resume: usize, opaque: usize) -> SbiResult<()> {
+    ecall(EXTENSION_ID, 3, [suspend.value(), resume, opaque, 0, 0, 0]).map(drop)
+}
@@ -0,0 +1,8 @@
+use super::{ecall, HartMask, SbiResult};
+
+pub const EXTENSION_ID: usize = 0x735049;
+
+/// Raises a supervisor software interrupt on every hart in `harts`
+pub fn send_ipi(harts: HartMask) -> SbiResult<()> {
+    unsafe { ecall(EXTENSION_ID, 0, [harts.mask, harts.base, 0, 0, 0, 0]).map(drop) }
+}
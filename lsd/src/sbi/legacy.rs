@@ -0,0 +1,11 @@
+//! Pre-0.2 calls, only used when the firmware doesn't have the replacement extension
+
+pub fn set_timer(deadline: u64) {
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") deadline as usize => _,
+            in("a7") 0,
+        );
+    }
+}
@@ -0,0 +1,182 @@
+//! Supervisor Binary Interface calls into the firmware
+//!
+//! `init` probes the Base extension once at boot, everything else checks `available`
+//! before relying on an extension
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+pub mod base;
+pub mod cppc;
+pub mod dbcn;
+pub mod hsm;
+pub mod ipi;
+pub mod legacy;
+pub mod pmu;
+pub mod rfence;
+pub mod srst;
+pub mod susp;
+pub mod time;
+
+static AVAILABLE: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SbiError {
+    Failed,
+    NotSupported,
+    InvalidParam,
+    Denied,
+    InvalidAddress,
+    AlreadyAvailable,
+    AlreadyStarted,
+    AlreadyStopped,
+    NoShmem,
+    InvalidState,
+    BadRange,
+    Timeout,
+    Io,
+    Unknown(isize),
+}
+
+impl SbiError {
+    pub(crate) fn from_code(code: isize) -> Self {
+        match code {
+            -1 => Self::Failed,
+            -2 => Self::NotSupported,
+            -3 => Self::InvalidParam,
+            -4 => Self::Denied,
+            -5 => Self::InvalidAddress,
+            -6 => Self::AlreadyAvailable,
+            -7 => Self::AlreadyStarted,
+            -8 => Self::AlreadyStopped,
+            -9 => Self::NoShmem,
+            -10 => Self::InvalidState,
+            -11 => Self::BadRange,
+            -12 => Self::Timeout,
+            -13 => Self::Io,
+            code => Self::Unknown(code),
+        }
+    }
+}
+
+pub type SbiResult<T> = Result<T, SbiError>;
+
+/// Extensions the kernel knows how to use
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Extension {
+    Time,
+    Ipi,
+    Rfence,
+    Hsm,
+    Srst,
+    Pmu,
+    Dbcn,
+    Susp,
+    Cppc,
+}
+
+impl Extension {
+    pub const ALL: [Extension; 9] = [
+        Self::Time,
+        Self::Ipi,
+        Self::Rfence,
+        Self::Hsm,
+        Self::Srst,
+        Self::Pmu,
+        Self::Dbcn,
+        Self::Susp,
+        Self::Cppc,
+    ];
+
+    pub const fn id(self) -> usize {
+        match self {
+            Self::Time => time::EXTENSION_ID,
+            Self::Ipi => ipi::EXTENSION_ID,
+            Self::Rfence => rfence::EXTENSION_ID,
+            Self::Hsm => hsm::EXTENSION_ID,
+            Self::Srst => srst::EXTENSION_ID,
+            Self::Pmu => pmu::EXTENSION_ID,
+            Self::Dbcn => dbcn::EXTENSION_ID,
+            Self::Susp => susp::EXTENSION_ID,
+            Self::Cppc => cppc::EXTENSION_ID,
+        }
+    }
+
+    const fn bit(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+/// A set of harts, `mask` bit N selects hart `base + N`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HartMask {
+    pub mask: usize,
+    pub base: usize,
+}
+
+impl HartMask {
+    pub const fn from_hart(hart: usize) -> Self {
+        Self { mask: 1, base: hart }
+    }
+
+    /// Every hart in the system, a base of -1 tells the firmware to ignore the mask
+    pub const fn all() -> Self {
+        Self { mask: 0, base: usize::MAX }
+    }
+}
+
+/// Probes the Base extension and logs what the firmware offers
+pub fn init() {
+    let version = base::spec_version();
+    let mut available = 0;
+
+    for extension in Extension::ALL {
+        if base::probe_extension(extension.id()) {
+            available |= extension.bit();
+        }
+    }
+
+    AVAILABLE.store(available, Ordering::Relaxed);
+
+    log::info!(
+        "SBI v{}.{}, implementation: {} v{:#x}",
+        version.major,
+        version.minor,
+        base::impl_name(base::impl_id()),
+        base::impl_version()
+    );
+
+    for extension in Extension::ALL {
+        log::info!("SBI {:?} extension: {}", extension, if available & extension.bit() != 0 { "available" } else { "missing" });
+    }
+}
+
+/// Whether the firmware reported `extension` during `init`
+pub fn available(extension: Extension) -> bool {
+    AVAILABLE.load(Ordering::Relaxed) & extension.bit() != 0
+}
+
+/// Performs a raw SBI call
+///
+/// # Safety
+/// The call can do anything the firmware allows, such as starting harts at arbitrary addresses
+pub unsafe fn ecall(extension: usize, function: usize, args: [usize; 6]) -> SbiResult<usize> {
+    let error: isize;
+    let value: usize;
+
+    core::arch::asm!(
+        "ecall",
+        inlateout("a0") args[0] => error,
+        inlateout("a1") args[1] => value,
+        in("a2") args[2],
+        in("a3") args[3],
+        in("a4") args[4],
+        in("a5") args[5],
+        in("a6") function,
+        in("a7") extension,
+    );
+
+    match error {
+        0 => Ok(value),
+        code => Err(SbiError::from_code(code)),
+    }
+}
@@ -0,0 +1,13 @@
+use super::{ecall, SbiResult};
+
+pub const EXTENSION_ID: usize = 0x504D55;
+
+/// Number of hardware and firmware counters
+pub fn num_counters() -> SbiResult<usize> {
+    unsafe { ecall(EXTENSION_ID, 0, [0; 6]) }
+}
+
+/// Raw description of a counter, see the PMU chapter of the spec for the layout
+pub fn counter_info(counter: usize) -> SbiResult<usize> {
+    unsafe { ecall(EXTENSION_ID, 1, [counter, 0, 0, 0, 0, 0]) }
+}
@@ -0,0 +1,19 @@
+use super::{ecall, HartMask, SbiResult};
+
+pub const EXTENSION_ID: usize = 0x52464E43;
+
+/// Runs `fence.i` on every hart in `harts`
+pub fn remote_fence_i(harts: HartMask) -> SbiResult<()> {
+    unsafe { ecall(EXTENSION_ID, 0, [harts.mask, harts.base, 0, 0, 0, 0]).map(drop) }
+}
+
+/// Runs `sfence.vma` over `[start, start + size)` on every hart in `harts`, a size of
+/// `usize::MAX` flushes the whole address space
+pub fn remote_sfence_vma(harts: HartMask, start: usize, size: usize) -> SbiResult<()> {
+    unsafe { ecall(EXTENSION_ID, 1, [harts.mask, harts.base, start, size, 0, 0]).map(drop) }
+}
+
+/// Like `remote_sfence_vma`, but only for translations tagged with `asid`
+pub fn remote_sfence_vma_asid(harts: HartMask, start: usize, size: usize, asid: usize) -> SbiResult<()> {
+    unsafe { ecall(EXTENSION_ID, 2, [harts.mask, harts.base, start, size, asid, 0]).map(drop) }
+}
@@ -0,0 +1,26 @@
+use super::{ecall, SbiError};
+
+pub const EXTENSION_ID: usize = 0x53525354;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(usize)]
+pub enum ResetType {
+    Shutdown = 0,
+    ColdReboot = 1,
+    WarmReboot = 2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(usize)]
+pub enum ResetReason {
+    NoReason = 0,
+    SystemFailure = 1,
+}
+
+/// Resets the system, only returns if the firmware refused
+pub fn system_reset(reset: ResetType, reason: ResetReason) -> SbiError {
+    match unsafe { ecall(EXTENSION_ID, 0, [reset as usize, reason as usize, 0, 0, 0, 0]) } {
+        Ok(_) => SbiError::Failed,
+        Err(err) => err,
+    }
+}
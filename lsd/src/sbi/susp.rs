@@ -0,0 +1,14 @@
+use super::{ecall, SbiResult};
+
+pub const EXTENSION_ID: usize = 0x53555350;
+
+/// Suspend to RAM, the only sleep type the spec defines
+pub const SUSPEND_TO_RAM: usize = 0;
+
+/// Suspends the whole system, every other hart has to be stopped first
+///
+/// # Safety
+/// The system resumes at `resume` like a hart started through HSM, with `opaque` in a1
+pub unsafe fn system_suspend(sleep_type: usize, resume: usize, opaque: usize) -> SbiResult<()> {
+    ecall(EXTENSION_ID, 0, [sleep_type, resume, opaque, 0, 0, 0]).map(drop)
+}
@@ -0,0 +1,8 @@
+use super::{ecall, SbiResult};
+
+pub const EXTENSION_ID: usize = 0x54494D45;
+
+/// Programs the timer of the current hart for the absolute time `deadline`
+pub fn set_timer(deadline: u64) -> SbiResult<()> {
+    unsafe { ecall(EXTENSION_ID, 0, [deadline as usize, 0, 0, 0, 0, 0]).map(drop) }
+}
@@ -0,0 +1,147 @@
+//! A tiny debug console reading lines from the UART
+
+use alloc::string::String;
+
+use crate::idle::{self, IdleState};
+use crate::io::console;
+use crate::process::{self, Pid};
+use crate::smp;
+use crate::user;
+
+/// Runs the console on its own thread, it sleeps while there's no input
+pub fn spawn() {
+    crate::thread::spawn("shell", || loop {
+        prompt();
+
+        let line = read_line();
+        execute(line.trim());
+    });
+}
+
+/// Reads a line from the console, echoing it back as it's typed
+pub fn read_line() -> String {
+    let mut line = String::new();
+
+    loop {
+        match console::read_byte() {
+            8 | 127 => {
+                if line.pop().is_some() {
+                    crate::log_print!("\x08 \x08");
+                }
+            },
+            b'\r' | b'\n' => {
+                crate::log_print!("\n");
+                return line;
+            },
+            byte if byte.is_ascii() && !byte.is_ascii_control() => {
+                line.push(byte as char);
+                crate::log_print!("{}", byte as char);
+            },
+            _ => {},
+        }
+    }
+}
+
+pub fn prompt() {
+    crate::log_print!("> ");
+}
+
+pub fn execute(line: &str) {
+    let mut args = line.split_whitespace();
+
+    match (args.next(), args.next(), args.next(), args.next()) {
+        (None, ..) => {},
+        (Some("help"), ..) => help(),
+        (Some("harts"), ..) => harts(),
+        (Some("ps"), None, ..) => ps(),
+        (Some("run"), ..) => run(line),
+        (Some("kill"), Some(pid), None, ..) => match pid.parse() {
+            Ok(pid) => match process::kill(Pid::from_usize(pid)) {
+                Ok(()) => crate::log_println!("Killed {}", pid),
+                Err(err) => crate::log_println!("Failed to kill {}: {:?}", pid, err),
+            },
+            Err(_) => crate::log_println!("Invalid pid: {}", pid),
+        },
+        (Some("hart"), Some("start"), Some(hart), None) => with_hart(hart, |hart| {
+            match smp::start(hart) {
+                Ok(()) => crate::log_println!("Hart {} starting", hart),
+                Err(err) => crate::log_println!("Failed to start hart {}: {:?}", hart, err),
+            }
+        }),
+        (Some("hart"), Some("stop"), Some(hart), None) => with_hart(hart, |hart| {
+            match smp::stop(hart) {
+                Ok(()) => crate::log_println!("Hart {} stopped", hart),
+                Err(err) => crate::log_println!("Failed to stop hart {}: {:?}", hart, err),
+            }
+        }),
+        (Some("hart"), Some("idle"), Some(hart), Some(state)) => with_hart(hart, |hart| {
+            match state {
+                "wfi" => idle::set_idle_state(hart, IdleState::Wfi),
+                "retentive" => idle::set_idle_state(hart, IdleState::Retentive),
+                "non-retentive" => idle::set_idle_state(hart, IdleState::NonRetentive),
+                _ => crate::log_println!("Unknown idle state: {}", state),
+            }
+        }),
+        (Some(command), ..) => crate::log_println!("Unknown command: {}, try help", command),
+    }
+}
+
+fn help() {
+    crate::log_println!("help                     this message");
+    crate::log_println!("harts                    list harts and their idle time");
+    crate::log_println!("hart start <id>          bring a hart online");
+    crate::log_println!("hart stop <id>           take a hart offline");
+    crate::log_println!("hart idle <id> <state>   idle through wfi, retentive, or non-retentive");
+    crate::log_println!("ps                       list processes");
+    crate::log_println!("kill <pid>               end a process");
+    crate::log_println!("run [args...]            start the init program again");
+}
+
+fn run(line: &str) {
+    // argv[0] is the program's name, in place of the command's
+    let argv: alloc::vec::Vec<&str> = core::iter::once("init").chain(line.split_whitespace().skip(1)).collect();
+
+    match user::start_init(&argv) {
+        Ok(process) => crate::log_println!("Started init as pid {}", process.pid()),
+        Err(err) => crate::log_println!("Failed to run init: {:?}", err),
+    }
+}
+
+fn ps() {
+    crate::log_println!("  PID  PPID  THREADS  STATE       NAME");
+
+    for process in process::list() {
+        let parent = match process.parent() {
+            Some(parent) => alloc::format!("{}", parent),
+            None => alloc::string::String::from("-"),
+        };
+
+        let state = match process.exit_status() {
+            Some(status) => alloc::format!("{:?}", status),
+            None if process.is_exiting() => alloc::string::String::from("exiting"),
+            None => alloc::string::String::from("running"),
+        };
+
+        crate::log_println!("{:>5} {:>5} {:>8}  {:<11} {}", process.pid(), parent, process.thread_count(), state, process.name());
+    }
+}
+
+fn harts() {
+    for hart in smp::harts() {
+        if hart >= crate::MAX_HARTS || !smp::is_online(hart) {
+            crate::log_println!("hart {}: offline", hart);
+            continue;
+        }
+
+        let stats = idle::stats(hart);
+
+        crate::log_println!("hart {}: online, idle {:?} ({:?}), busy {:?}", hart, idle::idle_state(hart), stats.idle, stats.busy);
+    }
+}
+
+fn with_hart(hart: &str, f: impl FnOnce(usize)) {
+    match hart.parse() {
+        Ok(hart) if hart < crate::MAX_HARTS => f(hart),
+        _ => crate::log_println!("Invalid hart: {}", hart),
+    }
+}
@@ -0,0 +1,129 @@
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::sbi::{self, ipi, Extension, HartMask};
+use crate::sync::IrqMutex;
+
+const SSIP: usize = 1 << 1;
+
+type Call = Box<dyn FnOnce() + Send>;
+
+const EMPTY: IrqMutex<VecDeque<Call>> = IrqMutex::new(VecDeque::new());
+
+static MAILBOXES: [IrqMutex<VecDeque<Call>>; crate::MAX_HARTS] = [EMPTY; crate::MAX_HARTS];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpiError {
+    InvalidHart(usize),
+    Offline(usize),
+    Sbi(sbi::SbiError),
+    NoMechanism,
+}
+
+/// Looks for an ACLINT SSWI device, otherwise IPIs go through SBI
+pub fn init(devicetree_ptr: *const u8) {
+    let fdt = unsafe {fdt::Fdt::from_ptr(devicetree_ptr).expect("Failed to get fdt")};
+
+    if let Some(sswi) = fdt.find_compatible(&["riscv,aclint-sswi"]) {
+        let region = sswi.reg().expect("No sswi region").next().unwrap();
+
+        crate::SSWI.store(region.starting_address.cast_mut() as *mut u32, Ordering::Relaxed);
+        log::info!("IPIs through ACLINT SSWI");
+    } else if sbi::available(Extension::Ipi) {
+        log::info!("IPIs through SBI");
+    } else {
+        log::warn!("No way to send IPIs");
+    }
+}
+
+/// Raises a software interrupt on `hart` without queueing anything
+pub fn send(hart: usize) -> Result<(), IpiError> {
+    let sswi = crate::SSWI.load(Ordering::Relaxed);
+
+    if !sswi.is_null() {
+        // One SETSSIP register per hart, writing 1 raises its SSIP
+        unsafe { sswi.add(hart).write_volatile(1) };
+
+        return Ok(());
+    }
+
+    if !sbi::available(Extension::Ipi) {
+        return Err(IpiError::NoMechanism);
+    }
+
+    ipi::send_ipi(HartMask::from_hart(hart)).map_err(IpiError::Sbi)
+}
+
+/// Queues `f` to run on `hart` from its software interrupt handler
+pub fn call(hart: usize, f: impl FnOnce() + Send + 'static) -> Result<(), IpiError> {
+    let mailbox = MAILBOXES.get(hart).ok_or(IpiError::InvalidHart(hart))?;
+
+    if !super::is_online(hart) {
+        return Err(IpiError::Offline(hart));
+    }
+
+    mailbox.lock().push_back(Box::new(f));
+
+    send(hart)
+}
+
+/// Like `call`, but spins until `f` has finished running on `hart`
+///
+/// Runs `f` directly when `hart` is the current hart
+pub fn call_sync(hart: usize, f: impl FnOnce() + Send + 'static) -> Result<(), IpiError> {
+    if hart == crate::HART_ID.load(Ordering::Relaxed) {
+        f();
+        return Ok(());
+    }
+
+    let done = Arc::new(AtomicBool::new(false));
+    let flag = done.clone();
+
+    call(hart, move || {
+        f();
+        flag.store(true, Ordering::Release);
+    })?;
+
+    while !done.load(Ordering::Acquire) {
+        // Keep serving our own mailbox so two harts calling each other can't deadlock
+        handle();
+        core::hint::spin_loop();
+    }
+
+    Ok(())
+}
+
+/// Runs `f` on every other online hart and waits for all of them to finish
+pub fn call_others_sync(f: impl Fn() + Send + Sync + 'static) {
+    let current = crate::HART_ID.load(Ordering::Relaxed);
+    let f = Arc::new(f);
+
+    for hart in (0..crate::MAX_HARTS).filter(|hart| *hart != current && super::is_online(*hart)) {
+        let f = f.clone();
+
+        if let Err(err) = call_sync(hart, move || f()) {
+            log::error!("Cross-hart call to hart {} failed: {:?}", hart, err);
+        }
+    }
+}
+
+/// Called from the software interrupt handler, acknowledges the IPI and drains the mailbox
+pub(crate) fn handle() {
+    unsafe {
+        core::arch::asm!("csrc sip, {}", in(reg) SSIP);
+    }
+
+    let hart = crate::HART_ID.load(Ordering::Relaxed);
+
+    loop {
+        // Popped one at a time so calls are free to queue more work
+        let call = MAILBOXES[hart].lock().pop_front();
+
+        match call {
+            None => break,
+            Some(call) => call(),
+        }
+    }
+}
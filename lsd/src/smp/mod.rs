@@ -0,0 +1,312 @@
+use alloc::alloc::{alloc_zeroed, Layout};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+
+use crate::sbi::{self, hsm, Extension};
+
+pub mod ipi;
+pub mod suspend;
+pub mod tlb;
+
+pub const STACK_SIZE: usize = 64 * 1024;
+pub const INT_STACK_SIZE: usize = 16 * 1024;
+
+const OFFLINE: AtomicBool = AtomicBool::new(false);
+const NO_BOOT_INFO: AtomicPtr<BootInfo> = AtomicPtr::new(core::ptr::null_mut());
+
+static DEVICETREE: AtomicPtr<u8> = AtomicPtr::new(core::ptr::null_mut());
+static ONLINE: [AtomicBool; crate::MAX_HARTS] = [OFFLINE; crate::MAX_HARTS];
+static ONLINE_COUNT: AtomicUsize = AtomicUsize::new(0);
+// Set from `start` until the hart marks itself online, so two starts can't both set it up
+static STARTING: [AtomicBool; crate::MAX_HARTS] = [OFFLINE; crate::MAX_HARTS];
+// Kept around so a hart that's stopped and restarted reuses its stacks
+static BOOT_INFO: [AtomicPtr<BootInfo>; crate::MAX_HARTS] = [NO_BOOT_INFO; crate::MAX_HARTS];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmpError {
+    InvalidHart(usize),
+    AlreadyOnline(usize),
+    NotOnline(usize),
+    /// The hart is partway through starting or stopping
+    Busy(usize),
+    /// Stopping the hart would leave nothing running
+    LastHart,
+    Sbi(sbi::SbiError),
+    Ipi(ipi::IpiError),
+}
+
+/// Everything a secondary hart needs before it can run Rust code, handed over through `hart_start`
+#[repr(C)]
+pub struct BootInfo {
+    stack_top: usize,
+    thread_pointer: usize,
+    satp: usize,
+    int_stack_top: usize,
+}
+
+/// Records the devicetree and marks the boot hart as online
+pub fn init(devicetree_ptr: *const u8) {
+    DEVICETREE.store(devicetree_ptr.cast_mut(), Ordering::Relaxed);
+    ipi::init(devicetree_ptr);
+
+    mark_online(crate::HART_ID.load(Ordering::Relaxed));
+}
+
+/// The devicetree the kernel was booted with
+pub fn devicetree() -> *const u8 {
+    DEVICETREE.load(Ordering::Relaxed)
+}
+
+pub fn is_online(hart: usize) -> bool {
+    ONLINE.get(hart).map(|online| online.load(Ordering::Acquire)).unwrap_or(false)
+}
+
+pub fn online_count() -> usize {
+    ONLINE_COUNT.load(Ordering::Relaxed)
+}
+
+/// Hart ids listed under `/cpus`
+pub fn harts() -> Vec<usize> {
+    let fdt = unsafe {fdt::Fdt::from_ptr(devicetree()).expect("Failed to get fdt")};
+
+    fdt.cpus().map(|cpu| cpu.ids().first()).collect()
+}
+
+/// Starts every other hart in the devicetree through HSM, waiting for each to finish its init
+pub fn start_secondaries() {
+    if !sbi::available(Extension::Hsm) {
+        log::warn!("SBI HSM unavailable, staying on a single hart");
+        return;
+    }
+
+    let boot_hart = crate::HART_ID.load(Ordering::Relaxed);
+
+    for hart in harts().into_iter().filter(|hart| *hart != boot_hart) {
+        if hart >= crate::MAX_HARTS {
+            log::warn!("Hart {} is past MAX_HARTS, leaving it stopped", hart);
+            continue;
+        }
+
+        match start(hart) {
+            Ok(()) => {
+                while !is_online(hart) {
+                    core::hint::spin_loop();
+                }
+            },
+            Err(err) => log::error!("Failed to start hart {}: {:?}", hart, err),
+        }
+    }
+
+    log::info!("{} harts online", online_count());
+}
+
+/// Starts `hart` at `_secondary_boot` with a fresh thread local block
+///
+/// A hart that was stopped before gets its old stacks back, but nothing from its old thread locals
+/// survives
+pub fn start(hart: usize) -> Result<(), SmpError> {
+    if hart >= crate::MAX_HARTS {
+        return Err(SmpError::InvalidHart(hart));
+    }
+
+    if is_online(hart) {
+        return Err(SmpError::AlreadyOnline(hart));
+    }
+
+    if STARTING[hart].swap(true, Ordering::AcqRel) {
+        return Err(SmpError::Busy(hart));
+    }
+
+    let result = start_stopped(hart);
+
+    if result.is_err() {
+        STARTING[hart].store(false, Ordering::Release);
+    }
+
+    result
+}
+
+fn start_stopped(hart: usize) -> Result<(), SmpError> {
+    // Only a hart the firmware has fully stopped is done with its stacks and thread locals,
+    // `stop` marks it offline well before that
+    match hsm::hart_status(hart) {
+        Ok(hsm::HartStatus::Stopped) => {},
+        Ok(hsm::HartStatus::Started) => return Err(SmpError::AlreadyOnline(hart)),
+        Ok(_) => return Err(SmpError::Busy(hart)),
+        Err(err) => return Err(SmpError::Sbi(err)),
+    }
+
+    let satp: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "csrr {}, satp",
+            out(reg) satp
+        );
+    }
+
+    let boot_info = match unsafe { BOOT_INFO[hart].load(Ordering::Acquire).as_mut() } {
+        Some(boot_info) => {
+            unsafe { crate::percpu::free(boot_info.thread_pointer as *mut u8) };
+            boot_info.thread_pointer = crate::percpu::alloc(hart) as usize;
+            boot_info.satp = satp;
+
+            boot_info
+        },
+        None => {
+            let boot_info = Box::leak(Box::new(BootInfo {
+                stack_top: alloc_stack(STACK_SIZE),
+                thread_pointer: crate::percpu::alloc(hart) as usize,
+                satp,
+                int_stack_top: alloc_stack(INT_STACK_SIZE),
+            }));
+
+            BOOT_INFO[hart].store(boot_info, Ordering::Release);
+
+            boot_info
+        }
+    };
+
+    unsafe {
+        hsm::hart_start(hart, _secondary_boot as unsafe extern "C" fn() -> ! as usize, boot_info as *mut BootInfo as usize).map_err(SmpError::Sbi)
+    }
+}
+
+/// Takes `hart` offline, waiting until the firmware reports it stopped
+///
+/// Its threads, timers, and external interrupts move to the harts that stay online. Stopping the
+/// current hart moves the calling thread as well, so this returns on another hart.
+pub fn stop(hart: usize) -> Result<(), SmpError> {
+    if hart >= crate::MAX_HARTS {
+        return Err(SmpError::InvalidHart(hart));
+    }
+
+    if !is_online(hart) {
+        return Err(SmpError::NotOnline(hart));
+    }
+
+    if online_count() == 1 {
+        return Err(SmpError::LastHart);
+    }
+
+    if hart == crate::HART_ID.load(Ordering::Relaxed) {
+        mark_offline(hart);
+        crate::thread::yield_now();
+    } else {
+        ipi::call(hart, || {
+            mark_offline(crate::HART_ID.load(Ordering::Relaxed));
+            crate::thread::scheduler::request_resched();
+        }).map_err(SmpError::Ipi)?;
+    }
+
+    loop {
+        match hsm::hart_status(hart) {
+            Ok(hsm::HartStatus::Stopped) => return Ok(()),
+            Ok(_) => core::hint::spin_loop(),
+            Err(err) => return Err(SmpError::Sbi(err)),
+        }
+    }
+}
+
+/// Takes the current hart offline for good, it can only come back through `start`
+///
+/// Called by the idle loop once the hart's been marked offline and every other thread has left
+/// it. Whatever is still queued on it, its timers, and its external interrupts are handed to an
+/// online hart first.
+pub fn stop_current() -> ! {
+    let hart = crate::HART_ID.load(Ordering::Relaxed);
+
+    unsafe {
+        core::arch::asm!(
+            "csrci sstatus, 2",
+            "csrw sie, zero"
+        );
+    }
+
+    if is_online(hart) {
+        mark_offline(hart);
+    }
+
+    crate::thread::scheduler::evacuate();
+
+    if let Some(target) = (0..crate::MAX_HARTS).find(|hart| is_online(*hart)) {
+        if let Err(err) = crate::timing::timer::migrate(target) {
+            log::error!("Failed to move hart {}'s timers to hart {}: {:?}", hart, target, err);
+        }
+
+        if let Err(err) = crate::interrupts::irq::retarget(hart, target) {
+            log::error!("Failed to move hart {}'s interrupts to hart {}: {:?}", hart, target, err);
+        }
+    }
+
+    // Calls queued before the hart went offline still get to run
+    ipi::handle();
+
+    log::info!("Hart {} stopping", hart);
+
+    let err = hsm::hart_stop();
+
+    log::error!("Hart {} failed to stop: {:?}", hart, err);
+    crate::hcf()
+}
+
+fn alloc_stack(size: usize) -> usize {
+    let stack = unsafe {alloc_zeroed(Layout::from_size_align(size, 16).unwrap())};
+
+    if stack.is_null() {
+        panic!("Out of memory allocating a hart stack");
+    }
+
+    stack as usize + size
+}
+
+fn mark_online(hart: usize) {
+    ONLINE[hart].store(true, Ordering::Release);
+    ONLINE_COUNT.fetch_add(1, Ordering::Relaxed);
+}
+
+fn mark_offline(hart: usize) {
+    ONLINE[hart].store(false, Ordering::Release);
+    ONLINE_COUNT.fetch_sub(1, Ordering::Relaxed);
+}
+
+/// Entered with the MMU off, a0 holding the hart id and a1 its `BootInfo`
+#[naked]
+#[no_mangle]
+unsafe extern "C" fn _secondary_boot() -> ! {
+    #[rustfmt::skip]
+    core::arch::asm!("
+        csrw sie, zero
+        csrci sstatus, 2
+
+        .option push
+        .option norelax
+        lla gp, __global_pointer$
+        .option pop
+
+        ld t0, 16(a1)
+        csrw satp, t0
+        sfence.vma
+
+        ld sp, 0(a1)
+        ld tp, 8(a1)
+
+        j {}
+    ", sym secondary_main, options(noreturn));
+}
+
+extern "C" fn secondary_main(hart: usize, boot_info: &'static BootInfo) -> ! {
+    crate::HART_ID.store(hart, Ordering::Relaxed);
+
+    crate::timing::init(devicetree());
+    crate::interrupts::init_hart(boot_info.int_stack_top as *mut u8);
+    crate::interrupts::irq::init_hart();
+    crate::idle::init_hart();
+    crate::thread::init_hart();
+
+    mark_online(hart);
+    STARTING[hart].store(false, Ordering::Release);
+
+    crate::core_bootstrap()
+}
@@ -0,0 +1,147 @@
+use crate::sbi::{self, hsm, Extension, SbiError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SuspendKind {
+    /// The hart keeps its state and `suspend` returns like `wfi`
+    Retentive,
+    /// The hart may lose everything, `suspend` saves and restores it around the call
+    NonRetentive,
+}
+
+/// What has to survive a non-retentive suspend, everything else is caller-saved anyway
+///
+/// Floating point state isn't kept, so nothing may have live FP registers while suspending
+#[repr(C)]
+#[derive(Default)]
+struct SuspendContext {
+    ra: usize, // offset 0
+    sp: usize, // offset 8
+    gp: usize, // offset 16
+    tp: usize, // offset 24
+    s: [usize; 12], // offset 32
+    sstatus: usize, // offset 128
+    sie: usize, // offset 136
+    stvec: usize, // offset 144
+    sscratch: usize, // offset 152
+    satp: usize, // offset 160
+}
+
+/// Suspends the current hart until an interrupt it has enabled in `sie` becomes pending
+///
+/// Should be called with interrupts disabled, the interrupt is taken once they're re-enabled
+pub fn suspend(kind: SuspendKind) -> Result<(), SbiError> {
+    if !sbi::available(Extension::Hsm) {
+        return Err(SbiError::NotSupported);
+    }
+
+    match kind {
+        SuspendKind::Retentive => unsafe {
+            hsm::hart_suspend(hsm::SuspendType::DEFAULT_RETENTIVE, 0, 0)
+        },
+        SuspendKind::NonRetentive => {
+            let mut context = SuspendContext::default();
+
+            match unsafe { suspend_non_retentive(&mut context) } {
+                0 => {
+                    // Interrupt files and the timer comparator aren't part of the saved state
+                    crate::interrupts::irq::init_hart();
+                    crate::timing::timer::reprogram();
+
+                    Ok(())
+                },
+                code => Err(sbi::SbiError::from_code(code)),
+            }
+        }
+    }
+}
+
+/// Saves the context and suspends, returns 0 after waking up or the SBI error if the call failed
+#[naked]
+unsafe extern "C" fn suspend_non_retentive(_context: &mut SuspendContext) -> isize {
+    #[rustfmt::skip]
+    core::arch::asm!("
+        sd ra, 0(a0)
+        sd sp, 8(a0)
+        sd gp, 16(a0)
+        sd tp, 24(a0)
+        sd s0, 32(a0)
+        sd s1, 40(a0)
+        sd s2, 48(a0)
+        sd s3, 56(a0)
+        sd s4, 64(a0)
+        sd s5, 72(a0)
+        sd s6, 80(a0)
+        sd s7, 88(a0)
+        sd s8, 96(a0)
+        sd s9, 104(a0)
+        sd s10, 112(a0)
+        sd s11, 120(a0)
+
+        csrr t0, sstatus
+        sd t0, 128(a0)
+        csrr t0, sie
+        sd t0, 136(a0)
+        csrr t0, stvec
+        sd t0, 144(a0)
+        csrr t0, sscratch
+        sd t0, 152(a0)
+        csrr t0, satp
+        sd t0, 160(a0)
+
+        mv a2, a0
+        li a0, {suspend_type}
+        lla a1, {resume}
+        li a6, 3
+        li a7, {hsm}
+        ecall
+
+        // Only reached if the suspend failed, a0 holds the error
+        ret
+    ",
+        suspend_type = const 0x8000_0000u32,
+        resume = sym suspend_resume,
+        hsm = const hsm::EXTENSION_ID,
+        options(noreturn)
+    );
+}
+
+/// Entered with the MMU off, a0 holding the hart id and a1 the saved context
+#[naked]
+unsafe extern "C" fn suspend_resume() -> ! {
+    #[rustfmt::skip]
+    core::arch::asm!("
+        ld t0, 160(a1)
+        csrw satp, t0
+        sfence.vma
+
+        ld t0, 128(a1)
+        csrw sstatus, t0
+        ld t0, 136(a1)
+        csrw sie, t0
+        ld t0, 144(a1)
+        csrw stvec, t0
+        ld t0, 152(a1)
+        csrw sscratch, t0
+
+        ld ra, 0(a1)
+        ld sp, 8(a1)
+        ld gp, 16(a1)
+        ld tp, 24(a1)
+        ld s0, 32(a1)
+        ld s1, 40(a1)
+        ld s2, 48(a1)
+        ld s3, 56(a1)
+        ld s4, 64(a1)
+        ld s5, 72(a1)
+        ld s6, 80(a1)
+        ld s7, 88(a1)
+        ld s8, 96(a1)
+        ld s9, 104(a1)
+        ld s10, 112(a1)
+        ld s11, 120(a1)
+
+        // Returns from `suspend_non_retentive` with success
+        li a0, 0
+        ret
+    ", options(noreturn));
+}
@@ -0,0 +1,76 @@
+use core::sync::atomic::Ordering;
+
+use crate::sbi::{self, rfence, Extension, HartMask};
+
+/// Past this many pages, flushing everything is cheaper than going page by page
+const FLUSH_ALL_PAGES: usize = 64;
+
+/// Flushes translations for `[start, start + size)` on the current hart
+///
+/// A `size` of `usize::MAX` means the whole address space, same as for SBI RFENCE
+pub fn flush_local(start: usize, size: usize) {
+    if size == usize::MAX || size / 4096 > FLUSH_ALL_PAGES {
+        return flush_local_all();
+    }
+
+    for page in (start..start.saturating_add(size)).step_by(4096) {
+        unsafe {
+            core::arch::asm!(
+                "sfence.vma {}, zero",
+                in(reg) page
+            );
+        }
+    }
+}
+
+/// Flushes every translation on the current hart
+pub fn flush_local_all() {
+    unsafe {
+        core::arch::asm!("sfence.vma zero, zero");
+    }
+}
+
+/// A range whose translations changed, still to be flushed on every hart
+///
+/// Kept apart from the change itself so it can be done after dropping the lock that guarded it,
+/// a hart spinning on that lock with interrupts disabled would never answer the IPI
+#[must_use = "other harts keep the old translations until this is flushed"]
+pub struct PendingFlush {
+    start: usize,
+    size: usize,
+}
+
+impl PendingFlush {
+    pub fn new(start: usize, size: usize) -> Self {
+        Self { start, size }
+    }
+
+    pub fn flush(self) {
+        shootdown(self.start, self.size);
+    }
+}
+
+/// Flushes `[start, start + size)` on every online hart, returning once they're all done
+///
+/// SBI RFENCE is used when the firmware has it, otherwise each hart is asked over an IPI
+pub fn shootdown(start: usize, size: usize) {
+    flush_local(start, size);
+
+    let current = crate::HART_ID.load(Ordering::Relaxed);
+    let others = (0..crate::MAX_HARTS)
+        .filter(|hart| *hart != current && super::is_online(*hart))
+        .fold(0, |mask, hart| mask | 1 << hart);
+
+    if others == 0 {
+        return;
+    }
+
+    if sbi::available(Extension::Rfence) {
+        match rfence::remote_sfence_vma(HartMask { mask: others, base: 0 }, start, size) {
+            Ok(()) => return,
+            Err(err) => log::warn!("SBI remote sfence.vma failed, falling back to IPIs: {:?}", err),
+        }
+    }
+
+    super::ipi::call_others_sync(move || flush_local(start, size));
+}
@@ -0,0 +1,41 @@
+use super::{MutexGuard, WaitQueue};
+
+/// A condition variable for use with the blocking `Mutex`
+///
+/// Wakeups can be spurious, so waits should be in a loop, or use `wait_while`
+pub struct Condvar {
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Self { waiters: WaitQueue::new() }
+    }
+
+    /// Releases the lock, blocks until notified, and takes the lock again
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex();
+
+        // Registering before the unlock means a notify from whoever takes the lock next can't be missed
+        self.waiters.wait_with(|| drop(guard));
+
+        mutex.lock()
+    }
+
+    /// Waits for as long as `condition` holds
+    pub fn wait_while<'a, T>(&self, mut guard: MutexGuard<'a, T>, mut condition: impl FnMut(&mut T) -> bool) -> MutexGuard<'a, T> {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+
+        guard
+    }
+
+    pub fn notify_one(&self) {
+        self.waiters.notify_one();
+    }
+
+    pub fn notify_all(&self) {
+        self.waiters.notify_all();
+    }
+}
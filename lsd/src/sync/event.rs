@@ -0,0 +1,30 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use super::WaitQueue;
+
+/// A one-shot event, once set every current and future `wait` returns straight away
+///
+/// `set` is safe from trap handlers
+pub struct Event {
+    set: AtomicBool,
+    waiters: WaitQueue,
+}
+
+impl Event {
+    pub const fn new() -> Self {
+        Self { set: AtomicBool::new(false), waiters: WaitQueue::new() }
+    }
+
+    pub fn set(&self) {
+        self.set.store(true, Ordering::Release);
+        self.waiters.notify_all();
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.set.load(Ordering::Acquire)
+    }
+
+    pub fn wait(&self) {
+        self.waiters.wait_until(|| self.is_set());
+    }
+}
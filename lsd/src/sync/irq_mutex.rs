@@ -0,0 +1,58 @@
+use core::ops::{Deref, DerefMut};
+
+use crate::interrupts::InterruptGuard;
+
+/// A spinlock that keeps interrupts disabled on the current hart while it's held
+///
+/// A trap handler taking a lock that the code it interrupted already holds would
+/// spin forever, masking interrupts for the lifetime of the guard rules that out
+pub struct IrqMutex<T> {
+    inner: spin::Mutex<T>,
+}
+
+/// Releases the lock, and then restores the previous interrupt state, when dropped
+pub struct IrqMutexGuard<'a, T> {
+    // Field order matters, the lock has to be released before interrupts come back on
+    guard: spin::MutexGuard<'a, T>,
+    _interrupts: InterruptGuard,
+}
+
+impl<T> IrqMutex<T> {
+    pub const fn new(data: T) -> Self {
+        Self { inner: spin::Mutex::new(data) }
+    }
+
+    pub fn lock(&self) -> IrqMutexGuard<'_, T> {
+        let interrupts = InterruptGuard::new();
+
+        IrqMutexGuard { guard: self.inner.lock(), _interrupts: interrupts }
+    }
+
+    pub fn try_lock(&self) -> Option<IrqMutexGuard<'_, T>> {
+        let interrupts = InterruptGuard::new();
+
+        self.inner.try_lock().map(|guard| IrqMutexGuard { guard, _interrupts: interrupts })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut()
+    }
+}
+
+impl<T> Deref for IrqMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for IrqMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
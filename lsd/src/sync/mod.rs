@@ -0,0 +1,13 @@
+mod condvar;
+mod event;
+mod irq_mutex;
+mod mutex;
+mod semaphore;
+mod wait_queue;
+
+pub use condvar::Condvar;
+pub use event::Event;
+pub use irq_mutex::{IrqMutex, IrqMutexGuard};
+pub use mutex::{Mutex, MutexGuard};
+pub use semaphore::Semaphore;
+pub use wait_queue::WaitQueue;
@@ -0,0 +1,34 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::WaitQueue;
+
+/// A counting semaphore, `release` is safe from trap handlers
+pub struct Semaphore {
+    count: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(count: usize) -> Self {
+        Self { count: AtomicUsize::new(count), waiters: WaitQueue::new() }
+    }
+
+    /// Takes a permit, blocking until one is available
+    pub fn acquire(&self) {
+        self.waiters.wait_until(|| self.try_acquire());
+    }
+
+    pub fn try_acquire(&self) -> bool {
+        self.count.fetch_update(Ordering::Acquire, Ordering::Relaxed, |count| count.checked_sub(1)).is_ok()
+    }
+
+    /// Hands back a permit, waking a waiter if there is one
+    pub fn release(&self) {
+        self.count.fetch_add(1, Ordering::Release);
+        self.waiters.notify_one();
+    }
+
+    pub fn available(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+}
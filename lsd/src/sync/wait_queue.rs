@@ -0,0 +1,117 @@
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+
+use super::IrqMutex;
+use crate::thread::{self, Thread};
+
+/// Threads waiting for something, woken in the order they started waiting
+///
+/// Waking is safe from trap handlers, waiting is only meaningful on a thread
+pub struct WaitQueue {
+    waiters: IrqMutex<VecDeque<Arc<Thread>>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self { waiters: IrqMutex::new(VecDeque::new()) }
+    }
+
+    /// Blocks until `condition` returns true
+    ///
+    /// Whoever makes the condition true has to notify the queue afterwards, the condition is
+    /// checked again after registering so a notify in between isn't missed
+    pub fn wait_until(&self, mut condition: impl FnMut() -> bool) {
+        if condition() {
+            return;
+        }
+
+        let current = match thread::try_current() {
+            Some(current) => current,
+            // Nothing to block before threads are up on this hart
+            None => {
+                while !condition() {
+                    core::hint::spin_loop();
+                }
+
+                return;
+            }
+        };
+
+        loop {
+            self.register(&current);
+
+            if condition() {
+                break;
+            }
+
+            thread::block();
+
+            if condition() {
+                break;
+            }
+        }
+
+        self.remove(&current);
+    }
+
+    /// Joins the queue, runs `release`, and blocks once
+    ///
+    /// Anything notified after `release` starts wakes this thread, but the wakeup can also be
+    /// spurious. This is what lets a condition variable drop its lock without losing a notify.
+    pub fn wait_with(&self, release: impl FnOnce()) {
+        let current = match thread::try_current() {
+            Some(current) => current,
+            None => return release(),
+        };
+
+        self.register(&current);
+        release();
+        thread::block();
+
+        self.remove(&current);
+    }
+
+    /// Wakes the longest waiting thread, returns false if nobody was waiting
+    pub fn notify_one(&self) -> bool {
+        let waiter = self.waiters.lock().pop_front();
+
+        match waiter {
+            None => false,
+            Some(waiter) => {
+                thread::unblock(&waiter);
+                true
+            }
+        }
+    }
+
+    /// Wakes every waiting thread, returns how many there were
+    pub fn notify_all(&self) -> usize {
+        let waiters = core::mem::take(&mut *self.waiters.lock());
+        let count = waiters.len();
+
+        for waiter in waiters {
+            thread::unblock(&waiter);
+        }
+
+        count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.waiters.lock().is_empty()
+    }
+
+    // Joins the queue unless a spurious wakeup left the thread in it, a notify that picked a
+    // duplicate would be lost on a thread that's already running
+    fn register(&self, thread: &Arc<Thread>) {
+        let mut waiters = self.waiters.lock();
+
+        if !waiters.iter().any(|waiter| Arc::ptr_eq(waiter, thread)) {
+            waiters.push_back(thread.clone());
+        }
+    }
+
+    // Drops a waiter that stopped waiting without being notified
+    fn remove(&self, thread: &Arc<Thread>) {
+        self.waiters.lock().retain(|waiter| !Arc::ptr_eq(waiter, thread));
+    }
+}
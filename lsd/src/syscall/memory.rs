@@ -0,0 +1,55 @@
+use super::{Errno, SyscallResult};
+use crate::mem::paging::entries::EntryFlags;
+use crate::mem::paging::PageSize;
+use crate::user;
+
+const PAGE_SIZE: usize = PageSize::Small as usize;
+
+/// Protection bits for `mmap`
+pub mod prot {
+    pub const READ: usize = 1 << 0;
+    pub const WRITE: usize = 1 << 1;
+    pub const EXEC: usize = 1 << 2;
+}
+
+/// Maps `len` bytes of zeroed memory at `addr`, or wherever there's room if `addr` is 0
+pub(super) fn mmap(addr: usize, len: usize, protection: usize) -> SyscallResult {
+    if len == 0 || protection & !(prot::READ | prot::WRITE | prot::EXEC) != 0 {
+        return Err(Errno::Inval);
+    }
+
+    // Write-only pages can't be expressed in a page table
+    if protection & prot::WRITE != 0 && protection & prot::READ == 0 {
+        return Err(Errno::Inval);
+    }
+
+    let len = len.checked_add(PAGE_SIZE - 1).ok_or(Errno::Inval)? & !(PAGE_SIZE - 1);
+
+    let address_space = crate::thread::current().address_space().ok_or(Errno::NoMem)?;
+
+    let addr = match addr {
+        0 => address_space.reserve(len).ok_or(Errno::NoMem)?,
+        addr if addr % PAGE_SIZE != 0 => return Err(Errno::Inval),
+        addr => addr,
+    };
+
+    if !user::is_user_range(addr, len) {
+        return Err(Errno::Inval);
+    }
+
+    let mut flags = EntryFlags::empty();
+
+    if protection & prot::READ != 0 {
+        flags |= EntryFlags::READ;
+    }
+    if protection & prot::WRITE != 0 {
+        flags |= EntryFlags::WRITE;
+    }
+    if protection & prot::EXEC != 0 {
+        flags |= EntryFlags::EXECUTE;
+    }
+
+    address_space.map_zeroed(addr, len, flags).map_err(|_| Errno::NoMem)?;
+
+    Ok((addr, 0))
+}
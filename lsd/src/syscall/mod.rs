@@ -0,0 +1,184 @@
+//! System calls from user mode
+//!
+//! The ABI: the call number goes in `a7` and up to six arguments in `a0` to `a5`. Results come
+//! back in `a0` and `a1`, a failed call returns the negated `Errno` in `a0`. Numbers and error
+//! values never change meaning once added, `lsd-user` mirrors them for programs.
+
+use core::time::Duration;
+
+use crate::interrupts::TrapFrame;
+use crate::process::{self, ExitStatus, Pid, ProcessError};
+use crate::thread;
+use crate::user::access::{self, UserAccessError};
+
+mod memory;
+
+pub use memory::prot;
+
+pub mod number {
+    pub const WRITE: usize = 1;
+    pub const EXIT: usize = 2;
+    pub const YIELD: usize = 3;
+    pub const SLEEP: usize = 4;
+    pub const MMAP: usize = 5;
+    pub const GET_TIME: usize = 6;
+    pub const GETPID: usize = 7;
+    pub const WAIT: usize = 8;
+    pub const KILL: usize = 9;
+}
+
+/// Why a system call failed, the values are shared with `lsd-user`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(usize)]
+pub enum Errno {
+    NoProcess = 3,
+    /// A blocking call gave up because the process is exiting
+    Intr = 4,
+    BadFd = 9,
+    NoChild = 10,
+    NoMem = 12,
+    Fault = 14,
+    Inval = 22,
+    NoSys = 38,
+}
+
+impl From<UserAccessError> for Errno {
+    fn from(_: UserAccessError) -> Self {
+        Self::Fault
+    }
+}
+
+impl From<ProcessError> for Errno {
+    fn from(err: ProcessError) -> Self {
+        match err {
+            ProcessError::NoSuchProcess => Self::NoProcess,
+            ProcessError::NoChildren => Self::NoChild,
+            ProcessError::Interrupted => Self::Intr,
+        }
+    }
+}
+
+pub type SyscallResult = Result<(usize, usize), Errno>;
+
+/// Clocks `get_time` can read
+pub mod clock {
+    /// Time since boot
+    pub const MONOTONIC: usize = 0;
+    /// Wall-clock time since the Unix epoch, needs an RTC
+    pub const REALTIME: usize = 1;
+}
+
+/// Runs the call described by `frame` and writes the results back into it
+///
+/// Called with interrupts enabled, outside of the trap's nesting accounting, since calls can block
+pub(crate) fn dispatch(frame: &mut TrapFrame) {
+    // Return past the ecall
+    frame.sepc += 4;
+
+    let regs = &frame.registers;
+    let args = [regs.a0, regs.a1, regs.a2, regs.a3, regs.a4, regs.a5];
+
+    let result = match regs.a7 {
+        number::WRITE => write(args[0], args[1], args[2]),
+        number::EXIT => exit(args[0]),
+        number::YIELD => sched_yield(),
+        number::SLEEP => sleep(args[0]),
+        number::MMAP => memory::mmap(args[0], args[1], args[2]),
+        number::GET_TIME => get_time(args[0]),
+        number::GETPID => getpid(),
+        number::WAIT => wait(args[0]),
+        number::KILL => kill(args[0]),
+        number => {
+            log::debug!("Unknown syscall {}", number);
+            Err(Errno::NoSys)
+        }
+    };
+
+    let (a0, a1) = match result {
+        Ok(values) => values,
+        Err(errno) => ((errno as usize).wrapping_neg(), 0),
+    };
+
+    frame.registers.a0 = a0;
+    frame.registers.a1 = a1;
+}
+
+fn write(fd: usize, ptr: usize, len: usize) -> SyscallResult {
+    if fd != 1 && fd != 2 {
+        return Err(Errno::BadFd);
+    }
+
+    let mut buffer = [0; 256];
+
+    for offset in (0..len).step_by(buffer.len()) {
+        let chunk = &mut buffer[..(len - offset).min(256)];
+
+        access::copy_from_user(chunk, ptr.wrapping_add(offset))?;
+        crate::io::logger::write_bytes(chunk);
+    }
+
+    Ok((len, 0))
+}
+
+fn exit(code: usize) -> SyscallResult {
+    log::debug!("User thread {} exited with {}", thread::current().name(), code as isize);
+
+    process::exit(ExitStatus::Exited(code as isize))
+}
+
+fn getpid() -> SyscallResult {
+    let pid = process::current().map_or(0, |process| process.pid().as_usize());
+
+    Ok((pid, 0))
+}
+
+/// Waits for a child, any of them if `pid` is 0, and returns its pid and encoded status
+///
+/// The status is encoded like a POSIX wait status: the exit code shifted left by 8, or the
+/// number of the signal a Unix kernel would have used, 9 for a kill and 11 for a fault
+fn wait(pid: usize) -> SyscallResult {
+    let pid = match pid {
+        0 => None,
+        pid => Some(Pid::from_usize(pid)),
+    };
+
+    let (pid, status) = process::wait(pid).map_err(Errno::from)?;
+
+    let status = match status {
+        ExitStatus::Exited(code) => (code as usize & 0xFF) << 8,
+        ExitStatus::Killed => 9,
+        ExitStatus::Faulted(_) => 11,
+    };
+
+    Ok((pid.as_usize(), status))
+}
+
+fn kill(pid: usize) -> SyscallResult {
+    process::kill(Pid::from_usize(pid)).map_err(Errno::from)?;
+
+    Ok((0, 0))
+}
+
+fn sched_yield() -> SyscallResult {
+    thread::yield_now();
+
+    Ok((0, 0))
+}
+
+fn sleep(nanos: usize) -> SyscallResult {
+    match thread::sleep_unless(Duration::from_nanos(nanos as u64), process::current_exiting) {
+        true => Ok((0, 0)),
+        false => Err(Errno::Intr),
+    }
+}
+
+/// Returns whole seconds in `a0` and the nanoseconds past them in `a1`
+fn get_time(clock: usize) -> SyscallResult {
+    let time = match clock {
+        clock::MONOTONIC => crate::timing::uptime(),
+        clock::REALTIME => crate::timing::wall_clock_now().ok_or(Errno::NoSys)?,
+        _ => return Err(Errno::Inval),
+    };
+
+    Ok((time.as_secs() as usize, time.subsec_nanos() as usize))
+}
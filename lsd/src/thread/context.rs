@@ -0,0 +1,60 @@
+/// Callee-saved registers, everything else is already saved by the caller of `switch`
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct Context {
+    pub ra: usize, // offset 0
+    pub sp: usize, // offset 8
+    pub s: [usize; 12], // offset 16
+}
+
+impl Context {
+    /// A context that starts running `entry` on the stack ending at `stack_top`
+    pub fn new(entry: extern "C" fn() -> !, stack_top: usize) -> Self {
+        Self {
+            ra: entry as usize,
+            sp: stack_top,
+            s: [0; 12],
+        }
+    }
+}
+
+/// Saves the current registers into `old` and resumes whatever `new` holds
+///
+/// Returns once something switches back to `old`
+#[naked]
+pub unsafe extern "C" fn switch(_old: *mut Context, _new: *const Context) {
+    #[rustfmt::skip]
+    core::arch::asm!("
+        sd ra, 0(a0)
+        sd sp, 8(a0)
+        sd s0, 16(a0)
+        sd s1, 24(a0)
+        sd s2, 32(a0)
+        sd s3, 40(a0)
+        sd s4, 48(a0)
+        sd s5, 56(a0)
+        sd s6, 64(a0)
+        sd s7, 72(a0)
+        sd s8, 80(a0)
+        sd s9, 88(a0)
+        sd s10, 96(a0)
+        sd s11, 104(a0)
+
+        ld ra, 0(a1)
+        ld sp, 8(a1)
+        ld s0, 16(a1)
+        ld s1, 24(a1)
+        ld s2, 32(a1)
+        ld s3, 40(a1)
+        ld s4, 48(a1)
+        ld s5, 56(a1)
+        ld s6, 64(a1)
+        ld s7, 72(a1)
+        ld s8, 80(a1)
+        ld s9, 88(a1)
+        ld s10, 96(a1)
+        ld s11, 104(a1)
+
+        ret
+    ", options(noreturn));
+}
@@ -0,0 +1,141 @@
+//! Lazy floating point switching
+//!
+//! Switching threads only saves the FP registers if the outgoing thread dirtied them, and leaves
+//! the FPU off for the incoming one. Its first FP instruction then traps as illegal, and
+//! `handle_trap` loads its registers and turns the FPU back on.
+
+use core::sync::atomic::Ordering;
+
+use crate::control_registers::Sstatus;
+
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct FpState {
+    f: [u64; 32], // offset 0
+    fcsr: u64, // offset 256
+}
+
+/// Saves the FP registers into `state` if they're dirty, then turns the FPU off
+pub(super) fn switch_out(state: *mut FpState) {
+    if Sstatus::read() & Sstatus::FS == Sstatus::FS_DIRTY {
+        unsafe { save(state) };
+    }
+
+    unsafe {
+        core::arch::asm!("csrc sstatus, {}", in(reg) Sstatus::FS.bits());
+    }
+}
+
+/// Called for illegal instruction traps, returns true if it was the FPU being off
+///
+/// `trapped_sstatus` is the `sstatus` the trap was taken with
+pub(crate) fn handle_trap(trapped_sstatus: usize) -> bool {
+    if trapped_sstatus & Sstatus::FS.bits() != 0 {
+        return false;
+    }
+
+    let thread = match super::try_current() {
+        None => return false,
+        Some(thread) => thread
+    };
+
+    unsafe {
+        core::arch::asm!("csrs sstatus, {}", in(reg) Sstatus::FS_INITIAL.bits());
+        restore(thread.fp.get());
+        // The trap exit keeps the live FS bits, so this is what the thread resumes with
+        core::arch::asm!("csrc sstatus, {}", in(reg) Sstatus::FS.bits());
+        core::arch::asm!("csrs sstatus, {}", in(reg) Sstatus::FS_CLEAN.bits());
+    }
+
+    log::trace!("Restored FP state of thread {:?} on hart {}", thread.id(), crate::HART_ID.load(Ordering::Relaxed));
+
+    true
+}
+
+#[naked]
+unsafe extern "C" fn save(_state: *mut FpState) {
+    #[rustfmt::skip]
+    core::arch::asm!("
+        .attribute arch, \"rv64imafdc\"
+        fsd f0, 0(a0)
+        fsd f1, 8(a0)
+        fsd f2, 16(a0)
+        fsd f3, 24(a0)
+        fsd f4, 32(a0)
+        fsd f5, 40(a0)
+        fsd f6, 48(a0)
+        fsd f7, 56(a0)
+        fsd f8, 64(a0)
+        fsd f9, 72(a0)
+        fsd f10, 80(a0)
+        fsd f11, 88(a0)
+        fsd f12, 96(a0)
+        fsd f13, 104(a0)
+        fsd f14, 112(a0)
+        fsd f15, 120(a0)
+        fsd f16, 128(a0)
+        fsd f17, 136(a0)
+        fsd f18, 144(a0)
+        fsd f19, 152(a0)
+        fsd f20, 160(a0)
+        fsd f21, 168(a0)
+        fsd f22, 176(a0)
+        fsd f23, 184(a0)
+        fsd f24, 192(a0)
+        fsd f25, 200(a0)
+        fsd f26, 208(a0)
+        fsd f27, 216(a0)
+        fsd f28, 224(a0)
+        fsd f29, 232(a0)
+        fsd f30, 240(a0)
+        fsd f31, 248(a0)
+        frcsr t0
+        sd t0, 256(a0)
+        .attribute arch, \"rv64imac\"
+        ret
+    ", options(noreturn));
+}
+
+#[naked]
+unsafe extern "C" fn restore(_state: *const FpState) {
+    #[rustfmt::skip]
+    core::arch::asm!("
+        .attribute arch, \"rv64imafdc\"
+        fld f0, 0(a0)
+        fld f1, 8(a0)
+        fld f2, 16(a0)
+        fld f3, 24(a0)
+        fld f4, 32(a0)
+        fld f5, 40(a0)
+        fld f6, 48(a0)
+        fld f7, 56(a0)
+        fld f8, 64(a0)
+        fld f9, 72(a0)
+        fld f10, 80(a0)
+        fld f11, 88(a0)
+        fld f12, 96(a0)
+        fld f13, 104(a0)
+        fld f14, 112(a0)
+        fld f15, 120(a0)
+        fld f16, 128(a0)
+        fld f17, 136(a0)
+        fld f18, 144(a0)
+        fld f19, 152(a0)
+        fld f20, 160(a0)
+        fld f21, 168(a0)
+        fld f22, 176(a0)
+        fld f23, 184(a0)
+        fld f24, 192(a0)
+        fld f25, 200(a0)
+        fld f26, 208(a0)
+        fld f27, 216(a0)
+        fld f28, 224(a0)
+        fld f29, 232(a0)
+        fld f30, 240(a0)
+        fld f31, 248(a0)
+        ld t0, 256(a0)
+        fscsr t0
+        .attribute arch, \"rv64imac\"
+        ret
+    ", options(noreturn));
+}
@@ -0,0 +1,306 @@
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+
+use crate::interrupts::InterruptGuard;
+use crate::mem::paging::AddressSpace;
+use crate::process::Pid;
+use crate::sync::IrqMutex;
+
+pub mod context;
+pub mod fp;
+pub mod scheduler;
+pub mod stack;
+
+pub use scheduler::{block, has_ready, sleep, sleep_unless, unblock};
+
+pub const DEFAULT_STACK_SIZE: usize = 64 * 1024;
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ThreadId(usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Priority {
+    Low,
+    Normal,
+    High,
+    Realtime,
+}
+
+impl Priority {
+    fn from_level(level: usize) -> Self {
+        match level {
+            0 => Self::Low,
+            1 => Self::Normal,
+            2 => Self::High,
+            _ => Self::Realtime,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum State {
+    Ready,
+    Running,
+    Blocked,
+    Exited,
+}
+
+pub struct Thread {
+    id: ThreadId,
+    name: String,
+    state: AtomicU8,
+    priority: AtomicU8,
+    idle: bool,
+    /// Hart it last ran on, where it's queued again when woken
+    hart: AtomicUsize,
+    /// Set while a hart is running it or still saving its registers
+    on_cpu: AtomicBool,
+    wake_token: AtomicBool,
+    /// Set by the `unblock` that made it ready, until it's back in a run queue
+    wake_pending: AtomicBool,
+    context: UnsafeCell<context::Context>,
+    fp: UnsafeCell<fp::FpState>,
+    // None for idle threads, they run on the stack their hart booted with
+    _stack: Option<stack::Stack>,
+    entry: IrqMutex<Option<Box<dyn FnOnce() + Send>>>,
+    joiners: IrqMutex<Vec<Arc<Thread>>>,
+    /// Pid of the process the thread runs for, 0 if none
+    process: AtomicUsize,
+    /// None for threads that only ever run in the kernel
+    address_space: IrqMutex<Option<Arc<AddressSpace>>>,
+}
+
+// `context` and `fp` are only touched by the hart switching to or from the thread
+unsafe impl Sync for Thread {}
+unsafe impl Send for Thread {}
+
+impl Thread {
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn state(&self) -> State {
+        match self.state.load(Ordering::Acquire) {
+            0 => State::Ready,
+            1 => State::Running,
+            2 => State::Blocked,
+            _ => State::Exited,
+        }
+    }
+
+    fn set_state(&self, state: State) {
+        self.state.store(state as u8, Ordering::Release);
+    }
+
+    pub fn priority(&self) -> Priority {
+        Priority::from_level(self.priority.load(Ordering::Relaxed) as usize)
+    }
+
+    /// Takes effect the next time the thread is queued
+    pub fn set_priority(&self, priority: Priority) {
+        self.priority.store(priority as u8, Ordering::Relaxed);
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.idle
+    }
+
+    pub fn process(&self) -> Option<Pid> {
+        match self.process.load(Ordering::Relaxed) {
+            0 => None,
+            pid => Some(Pid::from_usize(pid)),
+        }
+    }
+
+    pub(crate) fn set_process(&self, pid: Pid) {
+        self.process.store(pid.as_usize(), Ordering::Relaxed);
+    }
+
+    pub fn address_space(&self) -> Option<Arc<AddressSpace>> {
+        self.address_space.lock().clone()
+    }
+
+    /// Gives the thread its own address space, it's switched to whenever the thread runs
+    pub fn set_address_space(&self, address_space: Arc<AddressSpace>) {
+        *self.address_space.lock() = Some(address_space);
+    }
+
+    /// What `satp` has to be while the thread runs
+    fn satp(&self) -> usize {
+        match &*self.address_space.lock() {
+            Some(address_space) => address_space.satp(),
+            None => crate::mem::paging::kernel_satp(),
+        }
+    }
+
+    /// Top of the thread's own stack, which is also where its traps from user mode land
+    pub fn kernel_stack_top(&self) -> Option<usize> {
+        self._stack.as_ref().map(|stack| stack.top())
+    }
+
+    fn new(name: String, idle: bool, stack: Option<stack::Stack>, entry: Option<Box<dyn FnOnce() + Send>>) -> Self {
+        let context = match &stack {
+            Some(stack) => context::Context::new(thread_start, stack.top()),
+            // Filled in by the first switch away from it
+            None => context::Context::default(),
+        };
+
+        Self {
+            id: ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed)),
+            name,
+            state: AtomicU8::new(State::Running as u8),
+            priority: AtomicU8::new(Priority::Normal as u8),
+            idle,
+            hart: AtomicUsize::new(crate::HART_ID.load(Ordering::Relaxed)),
+            on_cpu: AtomicBool::new(false),
+            wake_token: AtomicBool::new(false),
+            wake_pending: AtomicBool::new(false),
+            context: UnsafeCell::new(context),
+            fp: UnsafeCell::new(fp::FpState::default()),
+            _stack: stack,
+            entry: IrqMutex::new(entry),
+            joiners: IrqMutex::new(Vec::new()),
+            process: AtomicUsize::new(0),
+            address_space: IrqMutex::new(None),
+        }
+    }
+}
+
+pub struct JoinHandle<T> {
+    thread: Arc<Thread>,
+    result: Arc<IrqMutex<Option<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn thread(&self) -> &Arc<Thread> {
+        &self.thread
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.thread.state() == State::Exited
+    }
+
+    /// Waits for the thread to exit and returns what it returned
+    pub fn join(self) -> T {
+        let current = current();
+
+        self.thread.joiners.lock().push(current);
+
+        while !self.is_finished() {
+            block();
+        }
+
+        self.result.lock().take().expect("Thread exited without a result")
+    }
+}
+
+/// Turns whatever is running on the current hart into its idle thread
+pub fn init_hart() {
+    let hart = crate::HART_ID.load(Ordering::Relaxed);
+    let idle = Arc::new(Thread::new(alloc::format!("idle{}", hart), true, None, None));
+
+    scheduler::init_hart(idle);
+}
+
+/// Starts a kernel thread running `f` with the default stack size
+pub fn spawn<F, T>(name: &str, f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    spawn_with(name, Priority::Normal, DEFAULT_STACK_SIZE, f)
+}
+
+pub fn spawn_with<F, T>(name: &str, priority: Priority, stack_size: usize, f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let result = Arc::new(IrqMutex::new(None));
+    let slot = result.clone();
+
+    let entry: Box<dyn FnOnce() + Send> = Box::new(move || {
+        let value = f();
+        *slot.lock() = Some(value);
+    });
+
+    let thread = Arc::new(Thread::new(name.to_string(), false, Some(stack::Stack::new(stack_size)), Some(entry)));
+    thread.set_priority(priority);
+
+    scheduler::spawn(thread.clone());
+
+    JoinHandle { thread, result }
+}
+
+/// The thread running on the current hart
+///
+/// Panics if `init_hart` hasn't run yet
+pub fn current() -> Arc<Thread> {
+    try_current().expect("Threads aren't set up on this hart")
+}
+
+pub fn try_current() -> Option<Arc<Thread>> {
+    scheduler::current()
+}
+
+/// Lets another ready thread run, if there is one
+pub fn yield_now() {
+    let _guard = InterruptGuard::new();
+
+    if let Some(current) = try_current() {
+        current.set_state(State::Ready);
+        drop(current);
+
+        scheduler::schedule(true);
+    }
+}
+
+/// Ends the current thread
+pub fn exit() -> ! {
+    let _guard = InterruptGuard::new();
+    let current = current();
+
+    if current.is_idle() {
+        panic!("Idle thread tried to exit");
+    }
+
+    current.set_state(State::Exited);
+
+    for joiner in core::mem::take(&mut *current.joiners.lock()) {
+        unblock(&joiner);
+    }
+
+    drop(current);
+
+    scheduler::schedule(false);
+
+    unreachable!("Exited thread was scheduled again");
+}
+
+extern "C" fn thread_start() -> ! {
+    scheduler::finish_switch();
+
+    let entry = current().entry.lock().take();
+
+    unsafe {
+        core::arch::asm!("csrsi sstatus, 2");
+    }
+
+    if let Some(entry) = entry {
+        entry();
+    }
+
+    exit()
+}
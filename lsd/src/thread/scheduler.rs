@@ -0,0 +1,449 @@
+//! Per-hart priority run queues with time slicing and work stealing
+//!
+//! Each hart picks the oldest thread of the highest non-empty priority in its own queue, and
+//! steals from the other harts once that's empty. A thread that runs for a whole `TIME_SLICE`
+//! gets preempted on the way out of the timer interrupt.
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+use super::{context, fp, Priority, State, Thread};
+use crate::smp::{self, ipi};
+use crate::sync::IrqMutex;
+use crate::timing::timer::{self, TimerId};
+
+pub const PRIORITY_LEVELS: usize = 4;
+pub const TIME_SLICE: Duration = Duration::from_millis(10);
+
+const EMPTY_LEVEL: VecDeque<Arc<Thread>> = VecDeque::new();
+const EMPTY_QUEUE: IrqMutex<RunQueue> = IrqMutex::new(RunQueue::new());
+
+static RUN_QUEUES: [IrqMutex<RunQueue>; crate::MAX_HARTS] = [EMPTY_QUEUE; crate::MAX_HARTS];
+
+// Only touched with interrupts disabled on the owning hart
+#[thread_local]
+static mut CURRENT: Option<Arc<Thread>> = None;
+#[thread_local]
+static mut IDLE: Option<Arc<Thread>> = None;
+// The thread that was just switched away from, and whether it goes straight back in the run
+// queue, dealt with by whoever runs next
+#[thread_local]
+static mut PREVIOUS: Option<(Arc<Thread>, bool)> = None;
+#[thread_local]
+static mut SLICE: Option<TimerId> = None;
+#[thread_local]
+static NEED_RESCHED: AtomicBool = AtomicBool::new(false);
+
+struct RunQueue {
+    levels: [VecDeque<Arc<Thread>>; PRIORITY_LEVELS],
+    len: usize,
+    /// Set once the hart is going offline, nothing gets queued on it after that
+    closed: bool,
+}
+
+impl RunQueue {
+    const fn new() -> Self {
+        Self { levels: [EMPTY_LEVEL; PRIORITY_LEVELS], len: 0, closed: false }
+    }
+
+    fn push(&mut self, thread: Arc<Thread>) {
+        self.levels[thread.priority() as usize].push_back(thread);
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<Arc<Thread>> {
+        let thread = self.levels.iter_mut().rev().find_map(|level| level.pop_front())?;
+        self.len -= 1;
+
+        Some(thread)
+    }
+
+    // Takes from the back, the thread least likely to run here soon
+    fn steal(&mut self) -> Option<Arc<Thread>> {
+        let thread = self.levels.iter_mut().rev().find_map(|level| level.pop_back())?;
+        self.len -= 1;
+
+        Some(thread)
+    }
+
+    fn highest(&self) -> Option<Priority> {
+        self.levels.iter().rposition(|level| !level.is_empty()).map(Priority::from_level)
+    }
+
+    fn drain(&mut self) -> Vec<Arc<Thread>> {
+        self.len = 0;
+        self.levels.iter_mut().rev().flat_map(|level| level.drain(..)).collect()
+    }
+}
+
+/// Makes `idle` the running and idle thread of the current hart
+pub(super) fn init_hart(idle: Arc<Thread>) {
+    idle.on_cpu.store(true, Ordering::Relaxed);
+    RUN_QUEUES[hart()].lock().closed = false;
+
+    unsafe {
+        IDLE = Some(idle.clone());
+        CURRENT = Some(idle);
+    }
+}
+
+pub(super) fn current() -> Option<Arc<Thread>> {
+    let _guard = crate::interrupts::InterruptGuard::new();
+
+    unsafe { (*core::ptr::addr_of!(CURRENT)).clone() }
+}
+
+fn hart() -> usize {
+    crate::HART_ID.load(Ordering::Relaxed)
+}
+
+/// Puts a new thread on the least loaded online hart
+pub(super) fn spawn(thread: Arc<Thread>) {
+    enqueue(least_loaded(), thread);
+}
+
+fn least_loaded() -> usize {
+    (0..crate::MAX_HARTS)
+        .filter(|hart| smp::is_online(*hart))
+        .min_by_key(|hart| RUN_QUEUES[*hart].lock().len)
+        .unwrap_or_else(hart)
+}
+
+/// Makes `thread` runnable on `target`, and makes sure someone notices
+fn enqueue(target: usize, thread: Arc<Thread>) {
+    let current = hart();
+    let priority = thread.priority();
+    let mut target = target;
+
+    thread.set_state(State::Ready);
+
+    let queued = loop {
+        let mut queue = RUN_QUEUES[target].lock();
+
+        // The hart went offline after it was picked, try another
+        if queue.closed {
+            drop(queue);
+            target = least_loaded();
+            continue;
+        }
+
+        thread.hart.store(target, Ordering::Relaxed);
+        queue.push(thread);
+
+        break queue.len;
+    };
+
+    if target != current {
+        // Wake it if it's sleeping, a busy hart gets to it at the end of its slice. Read after
+        // the push so a hart that's just about to sleep has either seen the work or is seen here
+        core::sync::atomic::fence(Ordering::SeqCst);
+
+        if crate::idle::is_sleeping(target) {
+            ipi::send(target).ok();
+        }
+
+        return;
+    }
+
+    if let Some(running) = self::current() {
+        if running.is_idle() || priority > running.priority() {
+            NEED_RESCHED.store(true, Ordering::Relaxed);
+        }
+    }
+
+    // More than this hart can run at once, let a sleeping hart steal some of it
+    if queued > 1 {
+        let sleeper = (0..crate::MAX_HARTS).find(|hart| *hart != current && smp::is_online(*hart) && crate::idle::is_sleeping(*hart));
+
+        if let Some(sleeper) = sleeper {
+            ipi::send(sleeper).ok();
+        }
+    }
+}
+
+/// Whether this hart has something to run, either queued locally or available to steal
+pub fn has_ready() -> bool {
+    (0..crate::MAX_HARTS).any(|hart| RUN_QUEUES[hart].lock().len > 0)
+}
+
+/// Switches to the next ready thread, or to the idle thread if there's none
+///
+/// With `requeue` the current thread stays runnable, and may just keep running. Otherwise it
+/// has blocked or exited, and always gets switched away from. Must be called with interrupts
+/// disabled.
+pub(super) fn schedule(requeue: bool) {
+    let current = match unsafe { (*core::ptr::addr_of!(CURRENT)).clone() } {
+        None => return,
+        Some(current) => current
+    };
+
+    NEED_RESCHED.store(false, Ordering::Relaxed);
+
+    // An offline hart only runs its idle thread, which takes it the rest of the way down
+    let keep = requeue && (current.is_idle() || smp::is_online(hart()));
+
+    let next = match pick(&current, keep) {
+        Some(next) => next,
+        None if keep => current.clone(),
+        None => unsafe { (*core::ptr::addr_of!(IDLE)).clone().expect("No idle thread") },
+    };
+
+    arm_slice(&next);
+
+    if Arc::ptr_eq(&current, &next) {
+        return current.set_state(State::Running);
+    }
+
+    fp::switch_out(current.fp.get());
+    next.set_state(State::Running);
+    next.hart.store(hart(), Ordering::Relaxed);
+    next.on_cpu.store(true, Ordering::Relaxed);
+
+    if let Some(top) = next.kernel_stack_top() {
+        crate::interrupts::set_kernel_stack(top);
+    }
+
+    crate::mem::paging::address_space::switch_to(next.satp());
+
+    let old = current.context.get();
+    let new = next.context.get();
+
+    unsafe {
+        CURRENT = Some(next);
+        PREVIOUS = Some((current, requeue));
+
+        context::switch(old, new);
+    }
+
+    finish_switch();
+}
+
+// Local queue first, unless the current thread outranks everything in it, then other harts
+fn pick(current: &Arc<Thread>, keep: bool) -> Option<Arc<Thread>> {
+    let this = hart();
+
+    if !smp::is_online(this) {
+        return None;
+    }
+
+    {
+        let mut queue = RUN_QUEUES[this].lock();
+
+        match queue.highest() {
+            Some(highest) if keep && !current.is_idle() && current.priority() > highest => return None,
+            Some(_) => return queue.pop(),
+            None => {}
+        }
+    }
+
+    // Keep running a thread that just yielded rather than pulling work across harts
+    if keep && !current.is_idle() {
+        return None;
+    }
+
+    (0..crate::MAX_HARTS)
+        .filter(|hart| *hart != this)
+        .find_map(|hart| RUN_QUEUES[hart].lock().steal())
+}
+
+// Threads get a fresh slice whenever they're switched to, idle threads don't need one
+fn arm_slice(next: &Arc<Thread>) {
+    unsafe {
+        if let Some(slice) = (*core::ptr::addr_of_mut!(SLICE)).take() {
+            timer::cancel(slice);
+        }
+
+        if !next.is_idle() {
+            SLICE = Some(timer::one_shot(TIME_SLICE, || NEED_RESCHED.store(true, Ordering::Relaxed)));
+        }
+    }
+}
+
+/// Called by whichever thread a switch lands in, once it's off the previous thread's stack
+pub(super) fn finish_switch() {
+    let previous = unsafe { (*core::ptr::addr_of_mut!(PREVIOUS)).take() };
+
+    if let Some((previous, requeue)) = previous {
+        // From here on the previous thread may be picked up by another hart
+        previous.on_cpu.store(false, Ordering::SeqCst);
+
+        // Idle threads never go in the run queue, they're what runs when it's empty. A thread
+        // that blocked goes back in only if an `unblock` found it still on this hart.
+        if (requeue && !previous.is_idle()) || previous.wake_pending.swap(false, Ordering::SeqCst) {
+            requeue_here(previous);
+        }
+
+        // Anything else is blocked, so someone else holds a reference, or exited, in which
+        // case this drops the last one and frees its stack
+    }
+}
+
+// Back in the current hart's queue, or another one's if this hart is going offline
+fn requeue_here(thread: Arc<Thread>) {
+    let mut queue = RUN_QUEUES[hart()].lock();
+
+    if queue.closed {
+        drop(queue);
+        return spawn(thread);
+    }
+
+    queue.push(thread);
+}
+
+/// Hands every thread queued on the current hart to the online harts
+///
+/// For when the hart is going offline, which it has to be marked as already. Nothing can be
+/// queued on it afterwards.
+pub(crate) fn evacuate() {
+    let threads = {
+        let mut queue = RUN_QUEUES[hart()].lock();
+        queue.closed = true;
+        queue.drain()
+    };
+
+    for thread in threads {
+        spawn(thread);
+    }
+}
+
+/// Makes the current hart reschedule on its way out of the trap being handled
+pub(crate) fn request_resched() {
+    NEED_RESCHED.store(true, Ordering::Relaxed);
+}
+
+/// Called on the way out of the outermost trap handler, switches threads if the slice ran out
+/// or something more important became ready
+pub(crate) fn preempt_if_needed() {
+    if crate::interrupts::depth() != 0 || !NEED_RESCHED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    if let Some(current) = current() {
+        let requeue = current.state() == State::Running;
+
+        if requeue {
+            current.set_state(State::Ready);
+        }
+
+        drop(current);
+        schedule(requeue);
+    }
+}
+
+/// Blocks the current thread until `unblock` is called on it
+///
+/// Like parking, wakeups can be spurious, so callers need to check their condition again.
+/// An `unblock` that comes before the `block` isn't lost.
+pub fn block() {
+    let current = match current() {
+        None => return,
+        Some(current) => current
+    };
+
+    // The idle thread always has to be runnable, so it just sleeps the hart instead
+    if current.is_idle() {
+        if current.wake_token.swap(false, Ordering::Acquire) {
+            return;
+        }
+
+        return crate::idle::idle_until(|| has_ready() || current.wake_token.swap(false, Ordering::Acquire));
+    }
+
+    let _guard = crate::interrupts::InterruptGuard::new();
+
+    if current.wake_token.swap(false, Ordering::Acquire) {
+        return;
+    }
+
+    current.set_state(State::Blocked);
+
+    // An unblock between the first check and the state change saw a running thread. If one got
+    // in after the state change it already made the thread ready, and it's queued again once
+    // it's off this hart.
+    if current.wake_token.swap(false, Ordering::Acquire) && current.state.compare_exchange(State::Blocked as u8, State::Running as u8, Ordering::AcqRel, Ordering::Acquire).is_ok() {
+        return;
+    }
+
+    drop(current);
+    schedule(false);
+}
+
+/// Makes a blocked thread runnable again, or makes its next `block` return straight away
+pub fn unblock(thread: &Arc<Thread>) {
+    thread.wake_token.store(true, Ordering::Release);
+
+    if thread.is_idle() {
+        let hart = thread.hart.load(Ordering::Relaxed);
+
+        if hart != self::hart() {
+            ipi::send(hart).ok();
+        }
+
+        return;
+    }
+
+    // Only whoever moves it out of Blocked queues it
+    if thread.state.compare_exchange(State::Blocked as u8, State::Ready as u8, Ordering::AcqRel, Ordering::Acquire).is_err() {
+        return;
+    }
+
+    // Its hart may still be saving its registers, in which case `finish_switch` there queues it
+    // once that's done. Exactly one side gets the pending wakeup back out.
+    thread.wake_pending.store(true, Ordering::SeqCst);
+
+    if thread.on_cpu.load(Ordering::SeqCst) || !thread.wake_pending.swap(false, Ordering::SeqCst) {
+        return;
+    }
+
+    let _guard = crate::interrupts::InterruptGuard::new();
+    let target = thread.hart.load(Ordering::Relaxed);
+
+    if smp::is_online(target) {
+        enqueue(target, thread.clone());
+    } else {
+        spawn(thread.clone());
+    }
+}
+
+/// Blocks the current thread for at least `duration`
+pub fn sleep(duration: Duration) {
+    sleep_unless(duration, || false);
+}
+
+/// Like `sleep`, but gives up early once `interrupted` returns true after a wakeup
+///
+/// Whoever makes `interrupted` true has to `unblock` the thread. Returns false if it was cut short.
+pub fn sleep_unless(duration: Duration, interrupted: impl Fn() -> bool) -> bool {
+    let current = match current() {
+        Some(current) if !current.is_idle() => current,
+        _ => {
+            crate::timing::sleep(duration);
+            return true;
+        }
+    };
+
+    let deadline = crate::timing::Instant::now() + duration;
+    // Weak so a long sleep that's cut short doesn't keep the thread around until the deadline
+    let waker = Arc::downgrade(&current);
+
+    drop(current);
+
+    timer::at(deadline, move || {
+        if let Some(thread) = waker.upgrade() {
+            unblock(&thread);
+        }
+    });
+
+    while crate::timing::Instant::now() < deadline {
+        if interrupted() {
+            return false;
+        }
+
+        block();
+    }
+
+    true
+}
@@ -0,0 +1,73 @@
+use alloc::alloc::{alloc, dealloc, Layout};
+
+use crate::mem::paging::{self, PageSize};
+use crate::mem::paging::entries::EntryFlags;
+use crate::mem::paging::physical_addr::PhyscialAddress;
+use crate::mem::paging::virtual_addr::VirtualAddress;
+
+const PAGE: usize = PageSize::Small as usize;
+
+/// A heap allocated kernel stack whose lowest page is left unmapped, so overflowing it faults
+pub struct Stack {
+    base: *mut u8,
+    size: usize,
+    guarded: bool,
+}
+
+unsafe impl Send for Stack {}
+unsafe impl Sync for Stack {}
+
+impl Stack {
+    /// Allocates `size` usable bytes, rounded up to whole pages, plus the guard page
+    pub fn new(size: usize) -> Self {
+        let size = (size + PAGE - 1) / PAGE * PAGE + PAGE;
+        let base = unsafe { alloc(Layout::from_size_align(size, PAGE).unwrap()) };
+
+        if base.is_null() {
+            panic!("Out of memory allocating a thread stack");
+        }
+
+        let unmapped = match paging::KERNEL_MAPPER.lock().as_mut() {
+            None => None,
+            Some(mapper) => mapper.unmap(VirtualAddress::new(base as u64), PageSize::Small).ok(),
+        };
+
+        // Only once the mapper is unlocked
+        let guarded = match unmapped {
+            None => false,
+            Some(flush) => {
+                flush.flush();
+                true
+            }
+        };
+
+        Self { base, size, guarded }
+    }
+
+    pub fn top(&self) -> usize {
+        self.base as usize + self.size
+    }
+
+    /// Lowest usable address, just above the guard page
+    pub fn bottom(&self) -> usize {
+        self.base as usize + PAGE
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        // The heap is identity mapped, the guard page has to go back before the allocator reuses it
+        if self.guarded {
+            let flags = EntryFlags::ACCESSED | EntryFlags::DIRTY | EntryFlags::READ | EntryFlags::WRITE | EntryFlags::VALID;
+            let addr = self.base as u64;
+
+            if let Some(mapper) = paging::KERNEL_MAPPER.lock().as_mut() {
+                mapper.recursive_map(PhyscialAddress::new(addr), VirtualAddress::new(addr), flags, PageSize::Small).expect("Failed to remap guard page");
+            }
+
+            crate::smp::tlb::flush_local(self.base as usize, PAGE);
+        }
+
+        unsafe { dealloc(self.base, Layout::from_size_align(self.size, PAGE).unwrap()) };
+    }
+}
@@ -1,27 +1,160 @@
+use alloc::sync::Arc;
+use core::convert::TryInto;
 use core::sync::atomic;
 use core::sync::atomic::Ordering;
+use core::time::Duration;
+
+mod instant;
+pub mod timer;
+
+pub use instant::Instant;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
 
 static FREQUENCY: atomic::AtomicUsize = atomic::AtomicUsize::new(0);
-pub static WAIT: atomic::AtomicBool = atomic::AtomicBool::new(false);
 
+#[thread_local]
+static BACKEND: atomic::AtomicU8 = atomic::AtomicU8::new(TimerBackend::Legacy as u8);
+
+/// How the supervisor timer of a hart gets programmed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TimerBackend {
+    /// `stimecmp` is written directly, without going through M-mode
+    Sstc,
+    /// SBI TIME extension
+    SbiTime,
+    /// Legacy SBI `set_timer` call
+    Legacy,
+}
+
+/// Reads the timebase and picks the timer backend for the current hart
 pub fn init(fdt_ptr: *const u8) {
+    let fdt = unsafe {fdt::Fdt::from_ptr(fdt_ptr).expect("Failed to get fdt")};
+    let hart = crate::HART_ID.load(Ordering::Relaxed);
+    let cpu = fdt.cpus().find(|cpu| cpu.ids().first() == hart).unwrap_or_else(|| fdt.cpus().next().unwrap());
+
+    FREQUENCY.store(cpu.timebase_frequency(), Ordering::Relaxed);
+
+    let backend = if has_sstc(&cpu) {
+        TimerBackend::Sstc
+    } else if crate::sbi::available(crate::sbi::Extension::Time) {
+        TimerBackend::SbiTime
+    } else {
+        TimerBackend::Legacy
+    };
+
+    BACKEND.store(backend as u8, Ordering::Relaxed);
+
+    log::info!("Hart {} timer: {:?}", hart, backend);
+}
+
+pub fn backend() -> TimerBackend {
+    match BACKEND.load(Ordering::Relaxed) {
+        0 => TimerBackend::Sstc,
+        1 => TimerBackend::SbiTime,
+        _ => TimerBackend::Legacy,
+    }
+}
+
+/// Ticks per second of the `time` CSR, zero until `init` has run
+pub fn frequency() -> usize {
+    FREQUENCY.load(Ordering::Relaxed)
+}
+
+/// Raw value of the `time` CSR
+pub fn ticks() -> u64 {
+    let ticks: u64;
+
     unsafe {
-        let fdt = fdt::Fdt::from_ptr(fdt_ptr).expect("Failed to get fdt");
-        let cpu = fdt.cpus().next().unwrap();
+        core::arch::asm!(
+            "rdtime {}",
+            out(reg) ticks
+        );
+    }
+
+    ticks
+}
 
-        FREQUENCY.store(cpu.timebase_frequency(), Ordering::Relaxed);
+pub fn ticks_to_duration(ticks: u64) -> Duration {
+    let frequency = frequency() as u128;
+
+    if frequency == 0 {
+        return Duration::ZERO;
     }
+
+    let nanos = ticks as u128 * NANOS_PER_SEC / frequency;
+
+    Duration::new((nanos / NANOS_PER_SEC) as u64, (nanos % NANOS_PER_SEC) as u32)
+}
+
+/// Converts `duration` into ticks, rounding up so a wait is never cut short
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let ticks = (duration.as_nanos() * frequency() as u128 + NANOS_PER_SEC - 1) / NANOS_PER_SEC;
+
+    ticks.try_into().unwrap_or(u64::MAX)
+}
+
+/// Time since the hart was reset
+pub fn uptime() -> Duration {
+    ticks_to_duration(ticks())
 }
 
+/// Time since the Unix epoch according to the RTC, None if there isn't one
+pub fn wall_clock_now() -> Option<Duration> {
+    crate::goldfish_rtc::now()
+}
+
+/// Blocks the current hart until `time` has passed
 pub fn wait(time: Time) {
-    sbi::legacy::set_timer(time.as_usize() as u64);
+    sleep(time.as_duration())
+}
+
+/// Blocks the current hart until `duration` has passed, other timers keep firing in the meantime
+///
+/// Threads should use `thread::sleep`, which lets other threads run on the hart instead
+pub fn sleep(duration: Duration) {
+    let done = Arc::new(atomic::AtomicBool::new(false));
+    let flag = done.clone();
+
+    timer::one_shot(duration, move || flag.store(true, Ordering::Relaxed));
 
-    WAIT.store(true, Ordering::Relaxed);
-    while WAIT.load(Ordering::Relaxed) {
-        super::wfi();
+    crate::idle::idle_until(|| done.load(Ordering::Relaxed));
+}
+
+/// Programs the supervisor timer of the current hart to fire at the absolute tick `deadline`
+pub(crate) fn set_deadline(deadline: u64) {
+    match backend() {
+        TimerBackend::Sstc => unsafe {
+            // stimecmp
+            core::arch::asm!(
+                "csrw 0x14D, {}",
+                in(reg) deadline
+            );
+        },
+        TimerBackend::SbiTime => {
+            if crate::sbi::time::set_timer(deadline).is_err() {
+                crate::sbi::legacy::set_timer(deadline);
+            }
+        },
+        TimerBackend::Legacy => crate::sbi::legacy::set_timer(deadline),
     }
 }
 
+/// Looks for Sstc in either the ISA string or the newer `riscv,isa-extensions` list
+fn has_sstc(cpu: &fdt::standard_nodes::Cpu) -> bool {
+    let in_isa = cpu.property("riscv,isa")
+        .and_then(|isa| isa.as_str())
+        .map(|isa| isa.split('_').skip(1).any(|ext| ext.eq_ignore_ascii_case("sstc")))
+        .unwrap_or(false);
+
+    let in_extensions = cpu.property("riscv,isa-extensions")
+        .map(|extensions| extensions.value.split(|b| *b == 0).any(|ext| ext.eq_ignore_ascii_case(b"sstc")))
+        .unwrap_or(false);
+
+    in_isa || in_extensions
+}
+
 pub enum Time {
     Hour(usize),
     Minute(usize),
@@ -32,12 +165,22 @@ pub enum Time {
 
 impl Time {
     pub fn as_usize(&self) -> usize {
-        match self {
-            Self::Hour(val) => val * FREQUENCY.load(Ordering::Relaxed) * 3600,
-            Self::Minute(val) => val * FREQUENCY.load(Ordering::Relaxed) * 60,
-            Self::Second(val) => val * FREQUENCY.load(Ordering::Relaxed),
-            Self::Millisecond(val) => (val / 1000) * FREQUENCY.load(Ordering::Relaxed), 
-            Self::Microsecond(val) => (val / 1000000) * FREQUENCY.load(Ordering::Relaxed), 
+        duration_to_ticks(self.as_duration()) as usize
+    }
+
+    pub fn as_duration(&self) -> Duration {
+        match *self {
+            Self::Hour(val) => Duration::from_secs(val as u64 * 3600),
+            Self::Minute(val) => Duration::from_secs(val as u64 * 60),
+            Self::Second(val) => Duration::from_secs(val as u64),
+            Self::Millisecond(val) => Duration::from_millis(val as u64),
+            Self::Microsecond(val) => Duration::from_micros(val as u64),
         }
     }
+}
+
+impl From<Time> for Duration {
+    fn from(time: Time) -> Duration {
+        time.as_duration()
+    }
 }
\ No newline at end of file
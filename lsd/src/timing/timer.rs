@@ -0,0 +1,145 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
+
+use super::Instant;
+use crate::sync::IrqMutex;
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+// Ordered by deadline first, the id keeps timers that expire on the same tick apart
+#[thread_local]
+static TIMERS: IrqMutex<BTreeMap<(u64, usize), Callback>> = IrqMutex::new(BTreeMap::new());
+// Periodic timers whose callback is running, so out of `TIMERS`, and whether they were cancelled meanwhile
+#[thread_local]
+static RUNNING: IrqMutex<BTreeMap<usize, bool>> = IrqMutex::new(BTreeMap::new());
+
+/// Identifies a timer on the hart that created it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TimerId(usize);
+
+enum Callback {
+    Once(Box<dyn FnOnce() + Send>),
+    Periodic { period: u64, callback: Box<dyn FnMut() + Send> },
+}
+
+/// Runs `callback` once from the timer interrupt after `delay` has passed
+pub fn one_shot(delay: Duration, callback: impl FnOnce() + Send + 'static) -> TimerId {
+    at(Instant::now() + delay, callback)
+}
+
+/// Runs `callback` once from the timer interrupt when `deadline` is reached
+pub fn at(deadline: Instant, callback: impl FnOnce() + Send + 'static) -> TimerId {
+    insert(deadline.ticks(), Callback::Once(Box::new(callback)))
+}
+
+/// Runs `callback` from the timer interrupt every `period` until it's cancelled
+pub fn periodic(period: Duration, callback: impl FnMut() + Send + 'static) -> TimerId {
+    // A zero period would keep the timer interrupt from ever returning
+    let period = super::duration_to_ticks(period).max(1);
+
+    insert(Instant::now().ticks() + period, Callback::Periodic { period, callback: Box::new(callback) })
+}
+
+/// Stops a timer created on the current hart, returns false if it already fired or doesn't exist
+pub fn cancel(id: TimerId) -> bool {
+    let mut timers = TIMERS.lock();
+    let key = timers.keys().find(|(_, timer)| *timer == id.0).copied();
+
+    match key {
+        None => match RUNNING.lock().get_mut(&id.0) {
+            None => false,
+            Some(cancelled) => {
+                // Caught while its callback runs, it's just not put back afterwards
+                *cancelled = true;
+                true
+            }
+        },
+        Some(key) => {
+            timers.remove(&key);
+            rearm(&timers);
+
+            true
+        }
+    }
+}
+
+/// When the earliest timer on the current hart expires
+pub fn next_deadline() -> Option<Instant> {
+    TIMERS.lock().keys().next().map(|(deadline, _)| Instant::from_ticks(*deadline))
+}
+
+/// Runs every expired timer on the current hart, then programs the timer for the next one
+pub(crate) fn handle_interrupt() {
+    loop {
+        let now = super::ticks();
+        let expired = {
+            let mut timers = TIMERS.lock();
+
+            match timers.keys().next() {
+                Some(&(deadline, id)) if deadline <= now => timers.remove(&(deadline, id)).map(|callback| (deadline, id, callback)),
+                _ => {
+                    rearm(&timers);
+                    None
+                }
+            }
+        };
+
+        // Callbacks run without the lock held so they're free to add or cancel timers
+        match expired {
+            None => break,
+            Some((_, _, Callback::Once(callback))) => callback(),
+            Some((deadline, id, Callback::Periodic { period, mut callback })) => {
+                RUNNING.lock().insert(id, false);
+                callback();
+
+                if RUNNING.lock().remove(&id) == Some(true) {
+                    continue;
+                }
+
+                // Skip any periods that were missed rather than firing for each of them
+                let next = deadline + ((now - deadline) / period + 1) * period;
+                TIMERS.lock().insert((next, id), Callback::Periodic { period, callback });
+            }
+        }
+    }
+}
+
+/// Hands every timer on the current hart to `hart`, for when the current hart goes offline
+pub(crate) fn migrate(hart: usize) -> Result<(), crate::smp::ipi::IpiError> {
+    let timers = core::mem::take(&mut *TIMERS.lock());
+
+    if timers.is_empty() {
+        return Ok(());
+    }
+
+    crate::smp::ipi::call(hart, move || {
+        let mut local = TIMERS.lock();
+
+        local.extend(timers);
+        rearm(&local);
+    })
+}
+
+/// Programs the timer for the earliest deadline again, for when the hardware may have lost it
+pub(crate) fn reprogram() {
+    rearm(&TIMERS.lock());
+}
+
+fn insert(deadline: u64, callback: Callback) -> TimerId {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let mut timers = TIMERS.lock();
+
+    timers.insert((deadline, id), callback);
+    rearm(&timers);
+
+    TimerId(id)
+}
+
+fn rearm(timers: &BTreeMap<(u64, usize), Callback>) {
+    // Nothing queued, push the deadline out as far as it goes so the interrupt stays quiet
+    let deadline = timers.keys().next().map(|(deadline, _)| *deadline).unwrap_or(u64::MAX);
+
+    super::set_deadline(deadline);
+}
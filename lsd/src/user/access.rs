@@ -0,0 +1,176 @@
+//! Copying to and from user memory
+//!
+//! Ranges are checked against the current address space first, but a page can still go away or
+//! lack the right permission, so the copy loop is listed in `__ex_table`. A fault on one of its
+//! accesses resumes at the fixup and the copy reports an error instead of taking down the kernel.
+
+use crate::control_registers::Sstatus;
+use crate::interrupts::InterruptGuard;
+use crate::mem::paging::PageSize;
+use crate::thread;
+use crate::utils::linker;
+
+const PAGE_SIZE: usize = PageSize::Small as usize;
+
+// Interrupts stay off while SUM is set, this bounds how long that lasts per copy
+const CHUNK_SIZE: usize = PAGE_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserAccessError {
+    /// Not inside the user region at all
+    OutOfRange,
+    /// Part of the range isn't mapped in the current address space
+    NotMapped,
+    /// Faulted part way through, on a page without the needed permission
+    Fault,
+}
+
+/// Lets the kernel touch user pages for as long as it's held
+///
+/// A thread switch doesn't save `sstatus`, so interrupts are kept off to stop SUM leaking
+/// into whatever would run next
+pub struct SumGuard {
+    _interrupts: InterruptGuard,
+}
+
+impl SumGuard {
+    pub fn new() -> Self {
+        let interrupts = InterruptGuard::new();
+
+        unsafe {
+            core::arch::asm!("csrs sstatus, {}", in(reg) Sstatus::SUM.bits());
+        }
+
+        Self { _interrupts: interrupts }
+    }
+}
+
+impl Drop for SumGuard {
+    fn drop(&mut self) {
+        // Runs before the interrupt guard is dropped
+        unsafe {
+            core::arch::asm!("csrc sstatus, {}", in(reg) Sstatus::SUM.bits());
+        }
+    }
+}
+
+/// Fills `dst` from user memory starting at `src`
+pub fn copy_from_user(dst: &mut [u8], src: usize) -> Result<(), UserAccessError> {
+    validate(src, dst.len())?;
+
+    for (index, chunk) in dst.chunks_mut(CHUNK_SIZE).enumerate() {
+        let from = src + index * CHUNK_SIZE;
+
+        let remaining = {
+            let _sum = SumGuard::new();
+
+            unsafe { copy_user(chunk.as_mut_ptr(), from as *const u8, chunk.len()) }
+        };
+
+        if remaining != 0 {
+            return Err(UserAccessError::Fault);
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes `src` to user memory starting at `dst`
+pub fn copy_to_user(dst: usize, src: &[u8]) -> Result<(), UserAccessError> {
+    validate(dst, src.len())?;
+
+    for (index, chunk) in src.chunks(CHUNK_SIZE).enumerate() {
+        let to = dst + index * CHUNK_SIZE;
+
+        let remaining = {
+            let _sum = SumGuard::new();
+
+            unsafe { copy_user(to as *mut u8, chunk.as_ptr(), chunk.len()) }
+        };
+
+        if remaining != 0 {
+            return Err(UserAccessError::Fault);
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks that `[addr, addr + len)` is in the user region and mapped in the current address space
+pub fn validate(addr: usize, len: usize) -> Result<(), UserAccessError> {
+    if len == 0 {
+        return Ok(());
+    }
+
+    if !super::is_user_range(addr, len) {
+        return Err(UserAccessError::OutOfRange);
+    }
+
+    let address_space = thread::try_current()
+        .and_then(|thread| thread.address_space())
+        .ok_or(UserAccessError::NotMapped)?;
+
+    let first = addr & !(PAGE_SIZE - 1);
+
+    for page in (first..addr + len).step_by(PAGE_SIZE) {
+        if address_space.translate(page).is_none() {
+            return Err(UserAccessError::NotMapped);
+        }
+    }
+
+    Ok(())
+}
+
+/// Where to resume after a fault at `sepc`, if it's one of the listed user accesses
+pub(crate) fn fixup(sepc: usize) -> Option<usize> {
+    let start = unsafe {linker::__ex_table_start.as_ptr()} as *const ExTableEntry;
+    let end = unsafe {linker::__ex_table_end.as_ptr()} as *const ExTableEntry;
+    let len = (end as usize - start as usize) / core::mem::size_of::<ExTableEntry>();
+
+    let table = unsafe { core::slice::from_raw_parts(start, len) };
+    let offset = linker::image_offset(sepc);
+
+    table.iter()
+        .find(|entry| linker::image_offset(entry.insn) == offset)
+        // Resume through the same alias of the kernel the fault happened in
+        .map(|entry| sepc - offset + linker::image_offset(entry.fixup))
+}
+
+/// An instruction that may fault on a user address, and where to go when it does
+#[repr(C)]
+struct ExTableEntry {
+    insn: usize,
+    fixup: usize,
+}
+
+/// Copies `len` bytes, returns how many were left when a fault stopped it, 0 on success
+#[naked]
+unsafe extern "C" fn copy_user(_dst: *mut u8, _src: *const u8, _len: usize) -> usize {
+    #[rustfmt::skip]
+    core::arch::asm!("
+        beqz a2, 5f
+
+        2:
+        3:  lbu t0, 0(a1)
+        4:  sb t0, 0(a0)
+            addi a0, a0, 1
+            addi a1, a1, 1
+            addi a2, a2, -1
+            bnez a2, 2b
+
+        5:
+            li a0, 0
+            ret
+
+        // Fixup, either access faulted
+        6:
+            mv a0, a2
+            ret
+
+        .pushsection __ex_table, \"a\"
+        .balign 8
+        .dword 3b, 6b
+        .dword 4b, 6b
+        .popsection
+    ", options(noreturn));
+}
@@ -0,0 +1,505 @@
+//! Loads ELF64 RISC-V executables into a fresh address space
+//!
+//! Static executables and static PIEs are supported. Anything asking for an interpreter, or
+//! with relocations other than `R_RISCV_RELATIVE`, is turned down.
+
+use alloc::alloc::{alloc_zeroed, dealloc, Layout};
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+
+use crate::mem::paging::entries::EntryFlags;
+use crate::mem::paging::{AddressSpace, PageSize};
+
+const PAGE_SIZE: usize = PageSize::Small as usize;
+const FRAME_LAYOUT: Layout = unsafe { Layout::from_size_align_unchecked(PAGE_SIZE, PAGE_SIZE) };
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+const EM_RISCV: u16 = 0xF3;
+
+const PHDR_SIZE: usize = 56;
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PT_INTERP: u32 = 3;
+const PT_PHDR: u32 = 6;
+
+const PF_X: u32 = 1 << 0;
+const PF_W: u32 = 1 << 1;
+const PF_R: u32 = 1 << 2;
+
+const DT_NULL: u64 = 0;
+const DT_RELA: u64 = 7;
+const DT_RELASZ: u64 = 8;
+const DT_RELAENT: u64 = 9;
+
+const R_RISCV_NONE: u32 = 0;
+const R_RISCV_RELATIVE: u32 = 3;
+
+const AT_NULL: usize = 0;
+const AT_PHDR: usize = 3;
+const AT_PHENT: usize = 4;
+const AT_PHNUM: usize = 5;
+const AT_PAGESZ: usize = 6;
+const AT_BASE: usize = 7;
+const AT_ENTRY: usize = 9;
+const AT_RANDOM: usize = 25;
+
+/// Where position independent executables get loaded
+pub const PIE_BASE: usize = super::USER_START;
+/// The top page of the user region stays unmapped
+pub const STACK_TOP: usize = super::USER_END - PAGE_SIZE;
+pub const STACK_SIZE: usize = 256 * 1024;
+const STACK_BOTTOM: usize = STACK_TOP - STACK_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    Truncated,
+    BadMagic,
+    NotElf64,
+    NotLittleEndian,
+    BadVersion,
+    WrongMachine(u16),
+    UnsupportedType(u16),
+    BadProgramHeader,
+    /// Dynamically linked, which needs an interpreter we don't have
+    Interpreter,
+    /// A segment or the entry point lies outside the user region, or a segment runs into the stack
+    OutOfRange,
+    BadDynamic,
+    UnsupportedRelocation(u32),
+    /// argv, envp and auxv don't fit on the stack
+    ArgumentsTooLarge,
+    OutOfMemory,
+}
+
+/// A loaded program, ready for `user::enter` or `user::run`
+pub struct Program {
+    pub address_space: Arc<AddressSpace>,
+    pub entry: usize,
+    pub stack_pointer: usize,
+}
+
+struct Header {
+    e_type: u16,
+    e_entry: usize,
+    e_phoff: usize,
+    e_phnum: usize,
+}
+
+#[derive(Clone, Copy)]
+struct ProgramHeader {
+    p_type: u32,
+    p_flags: u32,
+    p_offset: usize,
+    p_vaddr: usize,
+    p_filesz: usize,
+    p_memsz: usize,
+}
+
+/// Loads `image` into a new address space, with `argv` and `envp` on its stack
+pub fn load(image: &[u8], argv: &[&str], envp: &[&str]) -> Result<Program, ElfError> {
+    let header = parse_header(image)?;
+    let headers = (0..header.e_phnum)
+        .map(|index| {
+            let offset = header.e_phoff.checked_add(index * PHDR_SIZE).ok_or(ElfError::Truncated)?;
+
+            parse_program_header(image, offset)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if headers.iter().any(|phdr| phdr.p_type == PT_INTERP) {
+        return Err(ElfError::Interpreter);
+    }
+
+    let loads = headers.iter().filter(|phdr| phdr.p_type == PT_LOAD);
+
+    let bias = match header.e_type {
+        ET_DYN => {
+            let lowest = loads.clone().map(|phdr| phdr.p_vaddr).min().ok_or(ElfError::BadProgramHeader)?;
+
+            PIE_BASE.wrapping_sub(lowest & !(PAGE_SIZE - 1))
+        },
+        _ => 0,
+    };
+
+    let mut staging = Staging::new();
+
+    for phdr in loads {
+        load_segment(&mut staging, image, phdr, bias)?;
+    }
+
+    if let Some(dynamic) = headers.iter().find(|phdr| phdr.p_type == PT_DYNAMIC) {
+        relocate(&mut staging, dynamic, bias)?;
+    }
+
+    let entry = header.e_entry.wrapping_add(bias);
+
+    if !super::is_user_range(entry, 1) {
+        return Err(ElfError::OutOfRange);
+    }
+
+    let phdr = headers.iter()
+        .find(|phdr| phdr.p_type == PT_PHDR)
+        .map(|phdr| phdr.p_vaddr)
+        .or_else(|| headers.iter()
+            .filter(|phdr| phdr.p_type == PT_LOAD)
+            .find(|phdr| (phdr.p_offset..phdr.p_offset + phdr.p_filesz).contains(&header.e_phoff))
+            .map(|phdr| phdr.p_vaddr + (header.e_phoff - phdr.p_offset)))
+        .map(|vaddr| vaddr.wrapping_add(bias))
+        .unwrap_or(0);
+
+    let auxv = [
+        (AT_PHDR, phdr),
+        (AT_PHENT, PHDR_SIZE),
+        (AT_PHNUM, header.e_phnum),
+        (AT_PAGESZ, PAGE_SIZE),
+        (AT_BASE, 0),
+        (AT_ENTRY, entry),
+    ];
+
+    let stack_pointer = build_stack(&mut staging, argv, envp, &auxv)?;
+
+    let address_space = Arc::new(AddressSpace::new());
+    staging.commit(&address_space)?;
+
+    Ok(Program { address_space, entry, stack_pointer })
+}
+
+fn parse_header(image: &[u8]) -> Result<Header, ElfError> {
+    if image.len() < 64 {
+        return Err(ElfError::Truncated);
+    }
+
+    if &image[0..4] != ELF_MAGIC {
+        return Err(ElfError::BadMagic);
+    }
+
+    if image[4] != ELFCLASS64 {
+        return Err(ElfError::NotElf64);
+    }
+
+    if image[5] != ELFDATA2LSB {
+        return Err(ElfError::NotLittleEndian);
+    }
+
+    if image[6] != EV_CURRENT || read_u32(image, 20)? != EV_CURRENT as u32 {
+        return Err(ElfError::BadVersion);
+    }
+
+    let e_type = read_u16(image, 16)?;
+    let e_machine = read_u16(image, 18)?;
+
+    if e_machine != EM_RISCV {
+        return Err(ElfError::WrongMachine(e_machine));
+    }
+
+    if e_type != ET_EXEC && e_type != ET_DYN {
+        return Err(ElfError::UnsupportedType(e_type));
+    }
+
+    if read_u16(image, 54)? as usize != PHDR_SIZE {
+        return Err(ElfError::BadProgramHeader);
+    }
+
+    Ok(Header {
+        e_type,
+        e_entry: read_u64(image, 24)? as usize,
+        e_phoff: read_u64(image, 32)? as usize,
+        e_phnum: read_u16(image, 56)? as usize,
+    })
+}
+
+fn parse_program_header(image: &[u8], offset: usize) -> Result<ProgramHeader, ElfError> {
+    let phdr = ProgramHeader {
+        p_type: read_u32(image, offset)?,
+        p_flags: read_u32(image, offset + 4)?,
+        p_offset: read_u64(image, offset + 8)? as usize,
+        p_vaddr: read_u64(image, offset + 16)? as usize,
+        p_filesz: read_u64(image, offset + 32)? as usize,
+        p_memsz: read_u64(image, offset + 40)? as usize,
+    };
+
+    if phdr.p_type == PT_LOAD {
+        if phdr.p_filesz > phdr.p_memsz {
+            return Err(ElfError::BadProgramHeader);
+        }
+
+        match phdr.p_offset.checked_add(phdr.p_filesz) {
+            Some(end) if end <= image.len() => {},
+            _ => return Err(ElfError::Truncated),
+        }
+    }
+
+    Ok(phdr)
+}
+
+fn load_segment(staging: &mut Staging, image: &[u8], phdr: &ProgramHeader, bias: usize) -> Result<(), ElfError> {
+    let start = phdr.p_vaddr.wrapping_add(bias);
+
+    if phdr.p_memsz == 0 {
+        return Ok(());
+    }
+
+    // Everything from the stack up, guard page included, is off limits. Sharing pages with the
+    // stack would merge their permissions, and the stack gets written over afterwards anyway.
+    if !super::is_user_range(start, phdr.p_memsz) || start + phdr.p_memsz > STACK_BOTTOM {
+        return Err(ElfError::OutOfRange);
+    }
+
+    let mut flags = EntryFlags::empty();
+
+    // Write-only isn't expressible, so writable segments are readable too
+    if phdr.p_flags & (PF_R | PF_W) != 0 {
+        flags |= EntryFlags::READ;
+    }
+    if phdr.p_flags & PF_W != 0 {
+        flags |= EntryFlags::WRITE;
+    }
+    if phdr.p_flags & PF_X != 0 {
+        flags |= EntryFlags::EXECUTE;
+    }
+
+    // Frames start out zeroed, so touching the pages is all the bss needs
+    staging.touch(start, phdr.p_memsz, flags)?;
+    staging.write(start, &image[phdr.p_offset..phdr.p_offset + phdr.p_filesz])
+}
+
+fn relocate(staging: &mut Staging, dynamic: &ProgramHeader, bias: usize) -> Result<(), ElfError> {
+    let base = dynamic.p_vaddr.wrapping_add(bias);
+
+    let mut rela = None;
+    let mut rela_size = 0;
+    let mut rela_entry = 24;
+
+    for index in 0..dynamic.p_memsz / 16 {
+        let entry = offset(base, index * 16)?;
+        let tag = staging.read_u64(entry)?;
+        let value = staging.read_u64(offset(entry, 8)?)? as usize;
+
+        match tag {
+            DT_NULL => break,
+            DT_RELA => rela = Some(value.wrapping_add(bias)),
+            DT_RELASZ => rela_size = value,
+            DT_RELAENT => rela_entry = value,
+            _ => {},
+        }
+    }
+
+    let rela = match rela {
+        None => return Ok(()),
+        Some(rela) => rela,
+    };
+
+    if rela_entry < 24 {
+        return Err(ElfError::BadDynamic);
+    }
+
+    for index in 0..rela_size / rela_entry {
+        let entry = offset(rela, index * rela_entry)?;
+        let target = staging.read_u64(entry)? as usize;
+        let info = staging.read_u64(offset(entry, 8)?)?;
+        let addend = staging.read_u64(offset(entry, 16)?)? as usize;
+
+        match info as u32 {
+            R_RISCV_NONE => {},
+            R_RISCV_RELATIVE => staging.write(offset(target, bias)?, &bias.wrapping_add(addend).to_le_bytes())?,
+            kind => return Err(ElfError::UnsupportedRelocation(kind)),
+        }
+    }
+
+    Ok(())
+}
+
+// Addresses taken from the dynamic section, which can't be trusted not to wrap
+fn offset(addr: usize, offset: usize) -> Result<usize, ElfError> {
+    addr.checked_add(offset).ok_or(ElfError::BadDynamic)
+}
+
+/// Lays out argc, argv, envp and auxv the way the RISC-V psABI expects, returns the initial `sp`
+fn build_stack(staging: &mut Staging, argv: &[&str], envp: &[&str], auxv: &[(usize, usize)]) -> Result<usize, ElfError> {
+    let bottom = STACK_BOTTOM;
+
+    staging.touch(bottom, STACK_SIZE, EntryFlags::READ | EntryFlags::WRITE)?;
+
+    let mut sp = STACK_TOP;
+
+    let mut push = |staging: &mut Staging, bytes: &[u8]| -> Result<usize, ElfError> {
+        sp = sp.checked_sub(bytes.len()).filter(|sp| *sp >= bottom).ok_or(ElfError::ArgumentsTooLarge)?;
+        staging.write(sp, bytes)?;
+
+        Ok(sp)
+    };
+
+    let mut strings = |staging: &mut Staging, list: &[&str]| -> Result<Vec<usize>, ElfError> {
+        list.iter().map(|string| {
+            push(staging, &[0])?;
+            push(staging, string.as_bytes())
+        }).collect()
+    };
+
+    let argv_ptrs = strings(staging, argv)?;
+    let envp_ptrs = strings(staging, envp)?;
+
+    // Not cryptographic, but enough to seed stack protectors and the like
+    let mut seed = crate::timing::ticks() ^ 0x9E37_79B9_7F4A_7C15;
+    let mut random = [0; 16];
+
+    for chunk in random.chunks_mut(8) {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        chunk.copy_from_slice(&seed.to_le_bytes());
+    }
+
+    let random = push(staging, &random)?;
+
+    let mut words = Vec::new();
+    words.push(argv_ptrs.len());
+    words.extend_from_slice(&argv_ptrs);
+    words.push(0);
+    words.extend_from_slice(&envp_ptrs);
+    words.push(0);
+
+    for (key, value) in auxv.iter().copied().chain(core::iter::once((AT_RANDOM, random))) {
+        words.push(key);
+        words.push(value);
+    }
+
+    words.push(AT_NULL);
+    words.push(0);
+
+    let sp = (sp.checked_sub(words.len() * 8).ok_or(ElfError::ArgumentsTooLarge)?) & !15;
+
+    if sp < bottom {
+        return Err(ElfError::ArgumentsTooLarge);
+    }
+
+    for (index, word) in words.iter().enumerate() {
+        staging.write(sp + index * 8, &word.to_le_bytes())?;
+    }
+
+    Ok(sp)
+}
+
+/// Pages being filled in before the address space exists, freed unless they're committed
+struct Staging {
+    pages: BTreeMap<usize, (*mut u8, EntryFlags)>,
+}
+
+impl Staging {
+    fn new() -> Self {
+        Self { pages: BTreeMap::new() }
+    }
+
+    fn page(&mut self, page: usize, flags: EntryFlags) -> Result<*mut u8, ElfError> {
+        if let Some((frame, existing)) = self.pages.get_mut(&page) {
+            // Segments sharing a page get the union of their permissions
+            *existing |= flags;
+
+            return Ok(*frame);
+        }
+
+        let frame = unsafe { alloc_zeroed(FRAME_LAYOUT) };
+
+        if frame.is_null() {
+            return Err(ElfError::OutOfMemory);
+        }
+
+        self.pages.insert(page, (frame, flags));
+
+        Ok(frame)
+    }
+
+    fn touch(&mut self, start: usize, len: usize, flags: EntryFlags) -> Result<(), ElfError> {
+        let first = start & !(PAGE_SIZE - 1);
+
+        for page in (first..start + len).step_by(PAGE_SIZE) {
+            self.page(page, flags)?;
+        }
+
+        Ok(())
+    }
+
+    // Only for pages that are already staged, so stray relocations can't create new ones
+    fn frame(&self, addr: usize) -> Result<*mut u8, ElfError> {
+        let page = addr & !(PAGE_SIZE - 1);
+
+        match self.pages.get(&page) {
+            None => Err(ElfError::OutOfRange),
+            Some((frame, _)) => Ok(unsafe { frame.add(addr - page) }),
+        }
+    }
+
+    fn write(&mut self, addr: usize, bytes: &[u8]) -> Result<(), ElfError> {
+        for (index, byte) in bytes.iter().enumerate() {
+            let addr = addr.checked_add(index).ok_or(ElfError::OutOfRange)?;
+
+            unsafe { self.frame(addr)?.write(*byte) };
+        }
+
+        Ok(())
+    }
+
+    fn read_u64(&self, addr: usize) -> Result<u64, ElfError> {
+        let mut bytes = [0; 8];
+
+        for (index, byte) in bytes.iter_mut().enumerate() {
+            let addr = addr.checked_add(index).ok_or(ElfError::OutOfRange)?;
+
+            *byte = unsafe { self.frame(addr)?.read() };
+        }
+
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    /// Hands every page over to `address_space`
+    fn commit(mut self, address_space: &AddressSpace) -> Result<(), ElfError> {
+        while let Some((&page, &(frame, flags))) = self.pages.iter().next() {
+            self.pages.remove(&page);
+
+            if address_space.map_frame(page, frame as usize, flags).is_err() {
+                unsafe { dealloc(frame, FRAME_LAYOUT) };
+
+                return Err(ElfError::OutOfMemory);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Staging {
+    fn drop(&mut self) {
+        for (frame, _) in self.pages.values() {
+            unsafe { dealloc(*frame, FRAME_LAYOUT) };
+        }
+    }
+}
+
+fn read_u16(image: &[u8], offset: usize) -> Result<u16, ElfError> {
+    let end = offset.checked_add(2).ok_or(ElfError::Truncated)?;
+    let bytes = image.get(offset..end).ok_or(ElfError::Truncated)?;
+
+    Ok(u16::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u32(image: &[u8], offset: usize) -> Result<u32, ElfError> {
+    let end = offset.checked_add(4).ok_or(ElfError::Truncated)?;
+    let bytes = image.get(offset..end).ok_or(ElfError::Truncated)?;
+
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u64(image: &[u8], offset: usize) -> Result<u64, ElfError> {
+    let end = offset.checked_add(8).ok_or(ElfError::Truncated)?;
+    let bytes = image.get(offset..end).ok_or(ElfError::Truncated)?;
+
+    Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
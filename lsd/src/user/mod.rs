@@ -0,0 +1,161 @@
+//! Running threads in user mode
+//!
+//! A user thread is an ordinary kernel thread that dropped to U-mode. Its traps come back in on
+//! its own kernel stack, so it can block or be preempted there like any other thread.
+
+use crate::interrupts::TrapFrame;
+use alloc::sync::Arc;
+
+use crate::mem::paging::AddressSpace;
+use crate::process::Process;
+use crate::thread::{self, Thread};
+
+pub mod access;
+pub mod elf;
+
+/// Lowest address user mappings can go at
+pub const USER_START: usize = 0x10_0000_0000;
+/// End of the lower half of an Sv39 address space
+pub const USER_END: usize = 0x40_0000_0000;
+/// Where `mmap` starts placing mappings that don't ask for an address
+pub const MMAP_START: usize = 0x20_0000_0000;
+
+/// The init program the bootloader passed as the initrd
+static INIT_IMAGE: spin::Once<&'static [u8]> = spin::Once::new();
+
+#[derive(Debug)]
+pub enum InitError {
+    /// There was no initrd to run
+    NoImage,
+    Load(elf::ElfError),
+}
+
+/// Whether `[start, start + len)` lies entirely in the user region
+pub fn is_user_range(start: usize, len: usize) -> bool {
+    match start.checked_add(len) {
+        None => false,
+        Some(end) => start >= USER_START && end <= USER_END,
+    }
+}
+
+/// Drops the current thread into user mode at `entry`, with `stack_top` as its stack
+///
+/// Runs in the thread's address space, which is created empty if it doesn't have one yet.
+/// Whatever `entry` and the stack point at have to be mapped there.
+pub fn enter(entry: usize, stack_top: usize) -> ! {
+    let current = thread::current();
+
+    let kernel_stack_top = match current.kernel_stack_top() {
+        Some(top) => top,
+        None => panic!("Thread {} has no kernel stack to take user traps on", current.name()),
+    };
+
+    let address_space = match current.address_space() {
+        Some(address_space) => address_space,
+        None => {
+            let address_space = Arc::new(AddressSpace::new());
+            current.set_address_space(address_space.clone());
+            address_space
+        }
+    };
+
+    address_space.activate();
+
+    drop(address_space);
+    drop(current);
+
+    let frame = TrapFrame::new_user(entry, stack_top);
+
+    unsafe {
+        core::arch::asm!("csrci sstatus, 2");
+
+        crate::interrupts::return_to_user(&frame, kernel_stack_top)
+    }
+}
+
+/// Starts a thread that goes straight to user mode
+///
+/// It never returns a value to join on, it only ever exits or gets killed
+pub fn spawn(name: &str, entry: usize, stack_top: usize) -> Arc<Thread> {
+    thread::spawn(name, move || enter(entry, stack_top)).thread().clone()
+}
+
+/// Starts a thread running a loaded program
+pub fn run(name: &str, program: elf::Program) -> Arc<Thread> {
+    thread::spawn(name, move || {
+        thread::current().set_address_space(program.address_space);
+
+        enter(program.entry, program.stack_pointer)
+    }).thread().clone()
+}
+
+/// Finds the initrd the bootloader left in memory, it holds the init program
+///
+/// The image is used where it is, free memory is identity mapped and nothing allocates from it.
+pub fn init(devicetree_ptr: *const u8) {
+    let fdt = unsafe {fdt::Fdt::from_ptr(devicetree_ptr).expect("Failed to get fdt")};
+
+    let chosen = fdt.find_node("/chosen");
+    let start = chosen.and_then(|chosen| chosen.property("linux,initrd-start")).and_then(|prop| prop.as_usize());
+    let end = chosen.and_then(|chosen| chosen.property("linux,initrd-end")).and_then(|prop| prop.as_usize());
+
+    let (start, end) = match (start, end) {
+        (Some(start), Some(end)) if start < end => (start, end),
+        _ => {
+            log::info!("No initrd, there's no init program to run");
+            return;
+        }
+    };
+
+    let free = crate::mem::MEM_VEC.lock().find_id("free0").map(|free| free.range());
+
+    if !free.map_or(false, |free| free.start <= start as u64 && end as u64 <= free.end) {
+        log::warn!("Initrd at {:#x}..{:#x} is outside free memory, ignoring it", start, end);
+        return;
+    }
+
+    INIT_IMAGE.call_once(|| unsafe { core::slice::from_raw_parts(start as *const u8, end - start) });
+
+    log::info!("Init image: {} bytes at {:#x}", end - start, start);
+}
+
+/// Loads the init image and starts it as a new process with `argv`
+pub fn start_init(argv: &[&str]) -> Result<Arc<Process>, InitError> {
+    let image = INIT_IMAGE.get().ok_or(InitError::NoImage)?;
+    let program = elf::load(image, argv, &[]).map_err(InitError::Load)?;
+
+    Ok(crate::process::spawn("init", program))
+}
+
+pub(crate) fn log_fault(code: u64, frame: &TrapFrame) {
+    let stval: usize;
+
+    unsafe {
+        core::arch::asm!("csrr {}, stval", out(reg) stval);
+    }
+
+    let cause = match code {
+        0 => "instruction address misaligned",
+        1 => "instruction access fault",
+        2 => "illegal instruction",
+        3 => "breakpoint",
+        4 => "load address misaligned",
+        5 => "load access fault",
+        6 => "store/AMO address misaligned",
+        7 => "store/AMO access fault",
+        8 => "environment call",
+        12 => "instruction page fault",
+        13 => "load page fault",
+        15 => "store page fault",
+        _ => "unknown exception",
+    };
+
+    let name = thread::try_current().map(|thread| alloc::string::String::from(thread.name()));
+
+    log::error!("User thread {} killed: {} at {:#x}, stval {:#x}", name.as_deref().unwrap_or("?"), cause, frame.sepc, stval);
+}
+
+/// Ends the current user thread and its process, called once the faulting trap has been unwound
+pub(crate) fn kill_current(code: u64) -> ! {
+    crate::process::exit(crate::process::ExitStatus::Faulted(code))
+}
@@ -3,7 +3,27 @@ extern {
     pub static KERNEL_END: LinkerSymbol;
     pub static __tdata_start: LinkerSymbol;
     pub static __tdata_end: LinkerSymbol;
+    pub static __tbss_start: LinkerSymbol;
+    pub static __tbss_end: LinkerSymbol;
     pub static __global_pointer: LinkerSymbol;
+    pub static __ex_table_start: LinkerSymbol;
+    pub static __ex_table_end: LinkerSymbol;
+}
+
+/// Where virt.lds links the kernel
+pub const KERNEL_LINK_BASE: usize = 0xffff_ffff_8000_0000;
+/// Where the kernel image sits in physical memory, the same as `mem::paging::init` assumes
+pub const KERNEL_PHYS_BASE: usize = 0x8020_0000;
+
+/// Offset of a kernel address into the image, whether it's the linked alias or the physical one
+///
+/// Code reached through a function pointer stored in data runs at the linked address, anything
+/// reached PC-relative from boot runs at the physical one
+pub fn image_offset(addr: usize) -> usize {
+    match addr >= KERNEL_LINK_BASE {
+        true => addr - KERNEL_LINK_BASE,
+        false => addr.wrapping_sub(KERNEL_PHYS_BASE),
+    }
 }
 
 #[repr(C)]
@@ -5,6 +5,12 @@ enum Command {
     Run {
         #[structopt(long)]
         _debug: bool,
+        /// Interrupt controller to emulate: plic, aplic, or aplic-imsic
+        #[structopt(long, default_value = "plic")]
+        aia: String,
+        /// Number of harts to emulate
+        #[structopt(long, default_value = "4")]
+        smp: usize,
     },
     Build {
         #[structopt(long)]
@@ -19,15 +25,25 @@ fn build_kernel() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// The init program, the kernel gets it as the initrd
+fn build_init() -> anyhow::Result<()> {
+    let _dir = xshell::pushd("./lsd-user");
+    xshell::cmd!("cargo build --release --bin init").run()?;
+
+    Ok(())
+}
+
 fn main() -> anyhow::Result<()> {
     let args = Command::from_args();
 
     match args {
         Command::Build { _debug } => {
             build_kernel()?;
+            build_init()?;
         },
-        Command::Run { _debug } => {
+        Command::Run { _debug, aia, smp } => {
             build_kernel()?;
+            build_init()?;
 
             let debug_log: &[&str] = match true {
                 true => &["-D", "debug.log", "-d", "int,guest_errors"],
@@ -35,17 +51,20 @@ fn main() -> anyhow::Result<()> {
                 false => &[],
             };
 
-            let aia: &[&str] = match false {
-                true => &["-machine", "virt,aia=aplic"],
-                false => &["-machine", "virt"]
+            let aia: &[&str] = match aia.as_str() {
+                "aplic" => &["-machine", "virt,aia=aplic"],
+                "aplic-imsic" => &["-machine", "virt,aia=aplic-imsic"],
+                _ => &["-machine", "virt"]
             };
 
+            let smp = smp.to_string();
+
             #[rustfmt::skip]
             xshell::cmd!("
                 qemu-system-riscv64
                     {aia...}
                     -cpu rv64
-                    -smp 1
+                    -smp {smp}
                     -m 8G
                     -global virtio-mmio.force-legacy=false
                     -object rng-random,filename=/dev/urandom,id=rng0 
@@ -53,6 +72,7 @@ fn main() -> anyhow::Result<()> {
                     -device virtio-gpu-device
                     -bios opensbi-riscv64-generic-fw_jump.bin
                     -kernel lsd/target/riscv64gc-unknown-none-elf/release/lsd
+                    -initrd lsd-user/target/riscv64gc-unknown-none-elf/release/init
                     -serial mon:stdio
                     -no-reboot
                     {debug_log...}